@@ -39,7 +39,10 @@ fn criterion_benchmark(c: &mut Criterion) {
             };
             let mut runner = pollster::block_on(OfflineHeadless::<TreeSim>::new(
                 sim_params,
-                wgpu_n_body::sims::AddParams::TreeSimParams{ theta: 0.75 },
+                wgpu_n_body::sims::AddParams::TreeSimParams {
+                    theta: 0.75,
+                    collisions: false,
+                },
                 inits::uniform_init,
             ))
             .unwrap();
@@ -0,0 +1,280 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{ensure, Context};
+use glam::DVec3;
+use rayon::prelude::*;
+
+use crate::{
+    runners::OfflineHeadless,
+    sims::{self, AddParams, NaiveSim, Particle, SimParams, TreeSim},
+};
+
+/// What tree accelerations are compared against.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ForceReference {
+    /// One force evaluation of `NaiveSim` on the GPU, which shares the tree's f32 precision
+    NaiveSim,
+    /// CPU direct summation in f64, see `direct_accelerations`
+    Direct,
+}
+
+/// Distribution of the relative force error `|a - a_ref| / |a_ref|` over all particles with a
+/// non-zero reference acceleration.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ForceErrors {
+    pub median: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl ForceErrors {
+    /// Errors of the accelerations of `particles` against `reference`, which is indexed by
+    /// particle ID. `None` if no particle has a reference acceleration.
+    pub fn compare(particles: &[Particle], reference: &[DVec3]) -> Option<Self> {
+        let mut errors: Vec<f64> = particles
+            .par_iter()
+            .filter_map(|p| {
+                let expected = *reference.get(p.id as usize)?;
+                let norm = expected.length();
+                let actual = DVec3::from(p.acceleration.map(f64::from));
+                (norm > 0.0).then(|| (actual - expected).length() / norm)
+            })
+            .collect();
+        if errors.is_empty() {
+            return None;
+        }
+        errors.par_sort_unstable_by(f64::total_cmp);
+        // nearest-rank percentile
+        let percentile = |q: f64| errors[((q * errors.len() as f64).ceil() as usize).max(1) - 1];
+        Some(ForceErrors {
+            median: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: errors[errors.len() - 1],
+        })
+    }
+}
+
+/// Newtonian accelerations of `particles` by f64 direct summation, softened like the force
+/// kernels with `sim_params.e` (species softening and external potentials aren't applied).
+/// Indexed by particle ID, so IDs should be `0..particles.len()` like in `inits`.
+pub fn direct_accelerations(particles: &[Particle], sim_params: &SimParams) -> Vec<DVec3> {
+    let g = sim_params.g as f64;
+    let e = sim_params.e as f64;
+    let sources: Vec<(DVec3, f64)> = particles
+        .iter()
+        .filter(|p| !p.is_tracer())
+        .map(|p| (DVec3::from(p.position.map(f64::from)), p.mass as f64))
+        .collect();
+    let len = particles
+        .iter()
+        .map(|p| p.id as usize + 1)
+        .max()
+        .unwrap_or(0);
+    let mut accelerations = vec![DVec3::ZERO; len];
+    let computed: Vec<(u32, DVec3)> = particles
+        .par_iter()
+        .map(|p| {
+            let position = DVec3::from(p.position.map(f64::from));
+            let acc = sources
+                .iter()
+                .filter(|(source, _)| *source != position)
+                .fold(DVec3::ZERO, |acc, &(source, mass)| {
+                    let d = source - position;
+                    let r = d.length();
+                    acc + g * mass / (r * r * r + e) * d
+                });
+            (p.id, acc)
+        })
+        .collect();
+    for (id, acc) in computed {
+        accelerations[id as usize] = acc;
+    }
+    accelerations
+}
+
+/// Runs one `TreeSim` force evaluation per `theta` on a set of particles drawn from `init_fn`
+/// and compares the accelerations with `reference`. Particles don't move, every evaluation is a
+/// step with `dt = 0`.
+pub async fn theta_sweep(
+    sim_params: SimParams,
+    init_fn: fn(&SimParams) -> Vec<Particle>,
+    thetas: &[f32],
+    reference: ForceReference,
+) -> anyhow::Result<Vec<(f32, ForceErrors)>> {
+    let sim_params = SimParams {
+        dt: 0.0,
+        ..sim_params
+    };
+    let particles = init_fn(&sim_params);
+    ensure!(
+        particles.len() == sim_params.particle_num as usize,
+        "init_fn has to create particle_num particles"
+    );
+    let reference = match reference {
+        ForceReference::NaiveSim => {
+            let mut naive =
+                OfflineHeadless::<NaiveSim>::new(sim_params, AddParams::NaiveSimParams, init_fn)
+                    .await?;
+            naive.write_particles(&particles)?;
+            naive.step();
+            let mut evaluated = naive.read_particles();
+            sims::sort_by_id(&mut evaluated);
+            evaluated
+                .iter()
+                .map(|p| DVec3::from(p.acceleration.map(f64::from)))
+                .collect()
+        }
+        ForceReference::Direct => direct_accelerations(&particles, &sim_params),
+    };
+
+    let mut tree = OfflineHeadless::<TreeSim>::new(
+        sim_params,
+        AddParams::TreeSimParams {
+            theta: thetas.first().copied().unwrap_or(0.75),
+            collisions: false,
+        },
+        init_fn,
+    )
+    .await?;
+    let mut sweep = Vec::with_capacity(thetas.len());
+    for &theta in thetas {
+        tree.set_add_params(AddParams::TreeSimParams {
+            theta,
+            collisions: false,
+        })?;
+        tree.write_particles(&particles)?;
+        tree.step();
+        let errors = ForceErrors::compare(&tree.read_particles(), &reference)
+            .context("No particle has a non-zero reference acceleration")?;
+        sweep.push((theta, errors));
+    }
+    Ok(sweep)
+}
+
+/// Largest swept `theta` whose 99th percentile error stays below `max_p99`.
+pub fn choose_theta(sweep: &[(f32, ForceErrors)], max_p99: f64) -> Option<f32> {
+    sweep
+        .iter()
+        .filter(|(_, errors)| errors.p99 < max_p99)
+        .map(|&(theta, _)| theta)
+        .max_by(f32::total_cmp)
+}
+
+/// Writes one row per `(distribution, theta, errors)` entry.
+pub fn write_sweep(
+    path: impl AsRef<Path>,
+    rows: &[(&str, f32, ForceErrors)],
+) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create force error file {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    writeln!(out, "distribution,theta,median,p90,p99,max")?;
+    for (distribution, theta, e) in rows {
+        writeln!(
+            out,
+            "{},{},{},{},{},{}",
+            distribution, theta, e.median, e.p90, e.p99, e.max
+        )?;
+    }
+    out.flush().context("Failed to flush force error file")
+}
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::Zeroable;
+
+    use super::*;
+
+    fn particle(id: u32, position: [f32; 3], mass: f32, acceleration: [f32; 3]) -> Particle {
+        Particle {
+            position,
+            acceleration,
+            mass,
+            id,
+            ..Zeroable::zeroed()
+        }
+    }
+
+    #[test]
+    fn error_percentiles() {
+        // relative errors 0.01, 0.02, ..., 1.0 in shuffled order
+        let mut particles: Vec<Particle> = (0..100)
+            .map(|id| particle(id, [0.0; 3], 1.0, [1.0 + (id + 1) as f32 / 100.0, 0.0, 0.0]))
+            .collect();
+        particles.reverse();
+        particles.swap(3, 70);
+        let mut reference = vec![DVec3::X; 100];
+        // without a reference acceleration or outside the reference, both are skipped
+        particles.push(particle(100, [0.0; 3], 1.0, [5.0, 0.0, 0.0]));
+        reference.push(DVec3::ZERO);
+        particles.push(particle(500, [0.0; 3], 1.0, [5.0, 0.0, 0.0]));
+
+        let errors = ForceErrors::compare(&particles, &reference).unwrap();
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-6;
+        assert!(close(errors.median, 0.5), "{:?}", errors);
+        assert!(close(errors.p90, 0.9), "{:?}", errors);
+        assert!(close(errors.p99, 0.99), "{:?}", errors);
+        assert!(close(errors.max, 1.0), "{:?}", errors);
+        assert_eq!(ForceErrors::compare(&particles[100..], &reference), None);
+    }
+
+    #[test]
+    fn two_body_accelerations() {
+        let particles = [
+            particle(1, [1.0, 0.0, 0.0], 3.0, [0.0; 3]),
+            particle(0, [-1.0, 0.0, 0.0], 2.0, [0.0; 3]),
+            // feels both bodies but doesn't pull on them
+            particle(2, [0.0, 2.0, 0.0], 0.0, [0.0; 3]),
+        ];
+        let sim_params = SimParams {
+            particle_num: 3,
+            g: 0.5,
+            e: 0.0,
+            dt: 0.0,
+        };
+        let acc = direct_accelerations(&particles, &sim_params);
+        let close = |a: DVec3, b: DVec3| (a - b).length() < 1e-12;
+        assert!(close(acc[0], DVec3::new(0.375, 0.0, 0.0)), "{:?}", acc);
+        assert!(close(acc[1], DVec3::new(-0.25, 0.0, 0.0)), "{:?}", acc);
+        // 0.5 * (3 * (1, -2) + 2 * (-1, -2)) / sqrt(5)^3
+        let r3 = 5f64.powf(1.5);
+        let expected = DVec3::new(0.5 * (3.0 - 2.0) / r3, -0.5 * 5.0 * 2.0 / r3, 0.0);
+        assert!(close(acc[2], expected), "{:?}", acc);
+
+        let softened = direct_accelerations(
+            &particles,
+            &SimParams {
+                e: 1.0,
+                ..sim_params
+            },
+        );
+        assert!(close(
+            softened[0],
+            DVec3::new(0.5 * 3.0 * 2.0 / 9.0, 0.0, 0.0)
+        ));
+    }
+
+    #[test]
+    fn largest_theta_within_tolerance() {
+        let errors = |p99| ForceErrors {
+            median: 0.0,
+            p90: 0.0,
+            p99,
+            max: p99,
+        };
+        let sweep = [
+            (0.3, errors(0.001)),
+            (0.9, errors(0.05)),
+            (0.6, errors(0.01)),
+        ];
+        assert_eq!(choose_theta(&sweep, 0.02), Some(0.6));
+        assert_eq!(choose_theta(&sweep, 0.0001), None);
+    }
+}
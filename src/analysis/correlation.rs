@@ -0,0 +1,288 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{ensure, Context};
+use rand::{distributions::Uniform, prelude::Distribution, rngs::StdRng, SeedableRng};
+use rayon::prelude::*;
+
+use crate::sims::{BoundedNode, Octree, Particle};
+
+/// A logarithmic separation bin of a two-point correlation function.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CorrelationBin {
+    pub r_inner: f32,
+    pub r_outer: f32,
+    /// Ordered data-data, data-random and random-random pair counts
+    pub dd: u64,
+    pub dr: u64,
+    pub rr: u64,
+    /// Landy-Szalay estimate, NaN without random pairs
+    pub xi: f32,
+}
+
+/// Two-point correlation function of the massive particles (by number, not mass) in `bins`
+/// logarithmic bins between `r_min` and `r_max`, using the Landy-Szalay estimator
+/// `(DD - 2 DR + RR) / RR` with normalised pair counts. The random catalogue has
+/// `randoms_per_particle` times as many points, drawn uniformly from the bounding box of the
+/// particles with `seed`. Pairs are counted with a dual traversal of octrees built like the
+/// tree simulation's.
+pub fn correlation_function(
+    particles: &[Particle],
+    r_min: f32,
+    r_max: f32,
+    bins: usize,
+    randoms_per_particle: usize,
+    seed: u64,
+) -> anyhow::Result<Vec<CorrelationBin>> {
+    ensure!(
+        0.0 < r_min && r_min < r_max && bins > 0,
+        "correlation bins need 0 < r_min < r_max and at least one bin"
+    );
+    ensure!(
+        randoms_per_particle > 0,
+        "correlation function needs at least one random point per particle"
+    );
+    let data: Vec<Particle> = particles
+        .iter()
+        .filter(|p| !p.is_tracer())
+        .copied()
+        .collect();
+    if data.len() < 2 {
+        return Ok(Vec::new());
+    }
+
+    let mut min = [f32::INFINITY; 3];
+    let mut max = [f32::NEG_INFINITY; 3];
+    for p in &data {
+        for k in 0..3 {
+            min[k] = min[k].min(p.position[k]);
+            max[k] = max[k].max(p.position[k]);
+        }
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    let axes = [0, 1, 2].map(|k| Uniform::new_inclusive(min[k], max[k]));
+    let randoms: Vec<Particle> = (0..data.len() * randoms_per_particle)
+        .map(|ix| Particle {
+            position: [0, 1, 2].map(|k| axes[k].sample(&mut rng)),
+            mass: 1.0,
+            id: ix as u32,
+            ..data[0]
+        })
+        .collect();
+
+    let bins = Bins::new(r_min, r_max, bins);
+    let data_tree = Octree::build(&data);
+    let random_tree = Octree::build(&randoms);
+    let dd = bins.count_pairs(&data_tree, &data_tree);
+    let dr = bins.count_pairs(&data_tree, &random_tree);
+    let rr = bins.count_pairs(&random_tree, &random_tree);
+
+    let (n_d, n_r) = (data.len() as f64, randoms.len() as f64);
+    Ok((0..bins.count)
+        .map(|ix| {
+            let dd_norm = dd[ix] as f64 / (n_d * (n_d - 1.0));
+            let dr_norm = dr[ix] as f64 / (n_d * n_r);
+            let rr_norm = rr[ix] as f64 / (n_r * (n_r - 1.0));
+            CorrelationBin {
+                r_inner: bins.edge(ix) as f32,
+                r_outer: bins.edge(ix + 1) as f32,
+                dd: dd[ix],
+                dr: dr[ix],
+                rr: rr[ix],
+                xi: match rr[ix] {
+                    0 => f32::NAN,
+                    _ => ((dd_norm - 2.0 * dr_norm + rr_norm) / rr_norm) as f32,
+                },
+            }
+        })
+        .collect())
+}
+
+/// Writes one row per bin.
+pub fn write_correlation(path: impl AsRef<Path>, bins: &[CorrelationBin]) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create correlation file {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    writeln!(out, "r_inner,r_outer,dd,dr,rr,xi")?;
+    for b in bins {
+        writeln!(
+            out,
+            "{},{},{},{},{},{}",
+            b.r_inner, b.r_outer, b.dd, b.dr, b.rr, b.xi
+        )?;
+    }
+    out.flush().context("Failed to flush correlation file")
+}
+
+struct Bins {
+    log_min: f64,
+    log_step: f64,
+    r_min: f64,
+    r_max: f64,
+    count: usize,
+}
+
+impl Bins {
+    fn new(r_min: f32, r_max: f32, count: usize) -> Self {
+        let (r_min, r_max) = (r_min as f64, r_max as f64);
+        Bins {
+            log_min: r_min.ln(),
+            log_step: (r_max.ln() - r_min.ln()) / count as f64,
+            r_min,
+            r_max,
+            count,
+        }
+    }
+
+    fn edge(&self, ix: usize) -> f64 {
+        (self.log_min + self.log_step * ix as f64).exp()
+    }
+
+    fn index(&self, r: f64) -> Option<usize> {
+        match r >= self.r_min && r < self.r_max {
+            true => Some((((r.ln() - self.log_min) / self.log_step) as usize).min(self.count - 1)),
+            false => None,
+        }
+    }
+
+    /// Ordered pair counts between the particles of two trees, self pairs at zero separation
+    /// are never in a bin.
+    fn count_pairs(&self, a: &Octree, b: &Octree) -> Vec<u64> {
+        if a.is_empty() || b.is_empty() {
+            return vec![0; self.count];
+        }
+        // split the first tree into enough subtrees to keep every thread busy
+        let mut frontier = vec![a.root_node()];
+        while frontier.len() < 256 && frontier.iter().any(|node| !node.is_leaf()) {
+            frontier = frontier
+                .iter()
+                .flat_map(|&node| match node.is_leaf() {
+                    true => vec![node],
+                    false => a.child_nodes(node).collect(),
+                })
+                .collect();
+        }
+        frontier
+            .par_iter()
+            .fold(
+                || vec![0; self.count],
+                |mut counts, &node| {
+                    self.count_nodes(a, node, b, b.root_node(), &mut counts);
+                    counts
+                },
+            )
+            .reduce(
+                || vec![0; self.count],
+                |mut x, y| {
+                    x.iter_mut().zip(y).for_each(|(x, y)| *x += y);
+                    x
+                },
+            )
+    }
+
+    fn count_nodes(
+        &self,
+        a: &Octree,
+        node_a: BoundedNode,
+        b: &Octree,
+        node_b: BoundedNode,
+        counts: &mut [u64],
+    ) {
+        let (near, far) = node_a.separation(&node_b);
+        if near >= self.r_max || far < self.r_min {
+            return;
+        }
+        // every pair between the nodes falls into the same bin
+        if let (Some(ix), Some(far_ix)) = (self.index(near), self.index(far)) {
+            if ix == far_ix {
+                counts[ix] += node_a.bodies as u64 * node_b.bodies as u64;
+                return;
+            }
+        }
+        if node_a.is_leaf() && node_b.is_leaf() {
+            return;
+        }
+        match node_b.is_leaf() || (!node_a.is_leaf() && node_a.half_width >= node_b.half_width) {
+            true => {
+                for child in a.child_nodes(node_a) {
+                    self.count_nodes(a, child, b, node_b, counts);
+                }
+            }
+            false => {
+                for child in b.child_nodes(node_b) {
+                    self.count_nodes(a, node_a, b, child, counts);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::Zeroable;
+
+    use super::*;
+
+    fn point(id: usize, position: [f32; 3]) -> Particle {
+        Particle {
+            position,
+            mass: 1.0,
+            id: id as u32,
+            ..Zeroable::zeroed()
+        }
+    }
+
+    fn brute_force(bins: &Bins, a: &[Particle], b: &[Particle]) -> Vec<u64> {
+        let mut counts = vec![0; bins.count];
+        for p in a {
+            for q in b {
+                let r = (0..3)
+                    .map(|k| (p.position[k] as f64 - q.position[k] as f64).powi(2))
+                    .sum::<f64>()
+                    .sqrt();
+                if let Some(ix) = bins.index(r) {
+                    counts[ix] += 1;
+                }
+            }
+        }
+        counts
+    }
+
+    #[test]
+    fn pair_counts_match_brute_force() {
+        let bins = Bins::new(0.02, 1.0, 6);
+        let mut rng = StdRng::seed_from_u64(3);
+        let unit = Uniform::new(0.0, 1.0);
+        let mut data: Vec<Particle> = (0..300)
+            .map(|ix| point(ix, [0, 1, 2].map(|_| unit.sample(&mut rng))))
+            .collect();
+        // pairs just inside and just outside of an inner bin edge, away from the rest
+        let edge = bins.edge(2) as f32;
+        for (ix, x) in [0.0, edge * 0.999, edge * 1.001, 2.0 * edge]
+            .into_iter()
+            .enumerate()
+        {
+            data.push(point(300 + ix, [3.0 + x, 3.0, 3.0]));
+        }
+        let randoms: Vec<Particle> = (0..200)
+            .map(|ix| point(ix, [0, 1, 2].map(|_| unit.sample(&mut rng))))
+            .collect();
+
+        let data_tree = Octree::build(&data);
+        let random_tree = Octree::build(&randoms);
+        assert_eq!(
+            bins.count_pairs(&data_tree, &data_tree),
+            brute_force(&bins, &data, &data)
+        );
+        assert_eq!(
+            bins.count_pairs(&data_tree, &random_tree),
+            brute_force(&bins, &data, &randoms)
+        );
+        let straddling = brute_force(&bins, &data[300..], &data[300..]);
+        assert!(straddling[1] > 0 && straddling[2] > 0);
+    }
+}
@@ -0,0 +1,21 @@
+use crate::sims::Particle;
+
+/// Total kinetic energy `sum m v^2 / 2`.
+pub fn kinetic_energy(particles: &[Particle]) -> f64 {
+    particles
+        .iter()
+        .map(|p| {
+            let v2: f32 = p.velocity.iter().map(|v| v * v).sum();
+            0.5 * p.mass as f64 * v2 as f64
+        })
+        .sum()
+}
+
+/// Total interaction energy `sum m phi / 2` from `Particle::potential`, so it's only as current
+/// as the last force evaluation. External potentials aren't included.
+pub fn potential_energy(particles: &[Particle]) -> f64 {
+    particles
+        .iter()
+        .map(|p| 0.5 * p.mass as f64 * p.potential as f64)
+        .sum()
+}
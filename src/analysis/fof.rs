@@ -0,0 +1,297 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use anyhow::Context;
+use rayon::prelude::*;
+
+use crate::sims::{distance, Octree, Particle};
+
+/// Friends-of-friends settings. Particles closer than the linking length are friends, groups are
+/// the connected components of the friendship graph.
+#[derive(Copy, Clone, Debug)]
+pub struct FriendsOfFriends {
+    /// Linking length as a fraction of the mean interparticle spacing
+    pub linking_length: f32,
+    /// Smaller groups are dropped from the catalogue
+    pub min_members: usize,
+}
+
+impl Default for FriendsOfFriends {
+    fn default() -> Self {
+        FriendsOfFriends {
+            linking_length: 0.2,
+            min_members: 20,
+        }
+    }
+}
+
+/// A friends-of-friends group.
+#[derive(Clone, Debug)]
+pub struct Group {
+    pub mass: f32,
+    pub center_of_mass: [f32; 3],
+    /// Mass weighted mean velocity
+    pub velocity: [f32; 3],
+    /// Distance of the furthest member from the centre of mass
+    pub radius: f32,
+    /// Member IDs in ascending order
+    pub members: Vec<u32>,
+}
+
+/// Mean interparticle spacing `(V / N)^(1/3)` of the massive particles, where `V` is the volume
+/// of their bounding box. Flat extents are widened to a millionth of the largest one.
+pub fn mean_spacing(particles: &[Particle]) -> f32 {
+    let massive: Vec<&Particle> = particles.iter().filter(|p| !p.is_tracer()).collect();
+    if massive.is_empty() {
+        return 0.0;
+    }
+    let mut min = [f32::INFINITY; 3];
+    let mut max = [f32::NEG_INFINITY; 3];
+    for p in &massive {
+        for k in 0..3 {
+            min[k] = min[k].min(p.position[k]);
+            max[k] = max[k].max(p.position[k]);
+        }
+    }
+    let extents = [max[0] - min[0], max[1] - min[1], max[2] - min[2]];
+    let largest = extents[0].max(extents[1]).max(extents[2]);
+    let volume: f32 = extents.iter().map(|e| e.max(largest * 1e-6)).product();
+    (volume / massive.len() as f32).cbrt()
+}
+
+/// Groups of massive particles (tracers are ignored) with at least `min_members` members, most
+/// massive first.
+pub fn find_groups(particles: &[Particle], config: &FriendsOfFriends) -> Vec<Group> {
+    let linking_length = config.linking_length * mean_spacing(particles);
+    find_groups_with_length(particles, linking_length, config.min_members)
+}
+
+/// Like `find_groups` with an absolute linking length.
+pub fn find_groups_with_length(
+    particles: &[Particle],
+    linking_length: f32,
+    min_members: usize,
+) -> Vec<Group> {
+    if particles.iter().all(Particle::is_tracer) || linking_length <= 0.0 {
+        return Vec::new();
+    }
+
+    // friends are joined in the union-find as soon as they are found, from all threads. Queries
+    // in locality order keep each thread on one part of the tree.
+    let octree = Octree::build(particles);
+    let order = octree.locality_order();
+    let parents: Vec<AtomicU32> = (0..particles.len() as u32).map(AtomicU32::new).collect();
+    order.par_iter().for_each(|&a| {
+        for b in octree.within_radius(particles[a as usize].position, linking_length) {
+            if b > a {
+                union(&parents, a, b);
+            }
+        }
+    });
+    let mut components: HashMap<u32, Vec<u32>> = HashMap::new();
+    for &ix in &order {
+        let root = find(&parents, ix);
+        components.entry(root).or_default().push(ix);
+    }
+
+    let mut groups: Vec<Group> = components
+        .into_par_iter()
+        .map(|(_, component)| component)
+        .filter(|component| component.len() >= min_members)
+        .map(|component| {
+            let members: Vec<&Particle> = component
+                .iter()
+                .map(|&ix| &particles[ix as usize])
+                .collect();
+            let mass: f32 = members.iter().map(|p| p.mass).sum();
+            let mut center_of_mass = [0.0; 3];
+            let mut velocity = [0.0; 3];
+            for p in &members {
+                for k in 0..3 {
+                    center_of_mass[k] += p.position[k] * p.mass / mass;
+                    velocity[k] += p.velocity[k] * p.mass / mass;
+                }
+            }
+            let radius = members
+                .iter()
+                .map(|p| distance(&p.position, &center_of_mass))
+                .fold(0.0, f32::max);
+            let mut members: Vec<u32> = members.iter().map(|p| p.id).collect();
+            members.sort_unstable();
+            Group {
+                mass,
+                center_of_mass,
+                velocity,
+                radius,
+                members,
+            }
+        })
+        .collect();
+    groups.sort_by(|a, b| {
+        b.mass
+            .total_cmp(&a.mass)
+            .then(a.members[0].cmp(&b.members[0]))
+    });
+    groups
+}
+
+/// Writes one row per group (in catalogue order) with its properties and member count.
+pub fn write_catalogue(path: impl AsRef<Path>, groups: &[Group]) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create group catalogue {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    writeln!(out, "group,members,mass,cx,cy,cz,vx,vy,vz,radius")?;
+    for (ix, g) in groups.iter().enumerate() {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{},{}",
+            ix,
+            g.members.len(),
+            g.mass,
+            g.center_of_mass[0],
+            g.center_of_mass[1],
+            g.center_of_mass[2],
+            g.velocity[0],
+            g.velocity[1],
+            g.velocity[2],
+            g.radius
+        )?;
+    }
+    out.flush().context("Failed to flush group catalogue")
+}
+
+/// Writes one `group,id` row per member, with groups numbered like in `write_catalogue`.
+pub fn write_members(path: impl AsRef<Path>, groups: &[Group]) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create group member file {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    writeln!(out, "group,id")?;
+    for (ix, g) in groups.iter().enumerate() {
+        for id in &g.members {
+            writeln!(out, "{},{}", ix, id)?;
+        }
+    }
+    out.flush().context("Failed to flush group member file")
+}
+
+/// Union-find root with path halving, safe to call while other threads run `union`.
+fn find(parents: &[AtomicU32], mut ix: u32) -> u32 {
+    loop {
+        let parent = parents[ix as usize].load(Ordering::Acquire);
+        if parent == ix {
+            return ix;
+        }
+        let grandparent = parents[parent as usize].load(Ordering::Acquire);
+        // losing the race to another thread only skips this shortcut
+        let _ = parents[ix as usize].compare_exchange(
+            parent,
+            grandparent,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+        ix = grandparent;
+    }
+}
+
+/// Joins the sets of `a` and `b` without locks. Roots are only ever attached to smaller roots,
+/// so concurrent unions can't form cycles.
+fn union(parents: &[AtomicU32], a: u32, b: u32) {
+    loop {
+        let (root_a, root_b) = (find(parents, a), find(parents, b));
+        if root_a == root_b {
+            return;
+        }
+        let (child, root) = (root_a.max(root_b), root_a.min(root_b));
+        // fails if `child` stopped being a root in the meantime
+        if parents[child as usize]
+            .compare_exchange(child, root, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::Zeroable;
+
+    use super::*;
+
+    fn particles(positions: impl IntoIterator<Item = [f32; 3]>) -> Vec<Particle> {
+        positions
+            .into_iter()
+            .enumerate()
+            .map(|(id, position)| Particle {
+                position,
+                mass: 1.0,
+                id: id as u32,
+                ..Zeroable::zeroed()
+            })
+            .collect()
+    }
+
+    /// Cube of `side^3` points spaced `spacing` apart with its corner at `corner`.
+    fn lattice(corner: [f32; 3], side: usize, spacing: f32) -> Vec<[f32; 3]> {
+        (0..side * side * side)
+            .map(|ix| {
+                let cell = [ix % side, ix / side % side, ix / side / side];
+                [0, 1, 2].map(|k| corner[k] + cell[k] as f32 * spacing)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn separated_clusters() {
+        let mut positions = lattice([-0.15; 3], 4, 0.1);
+        positions.extend(lattice([10.0, 0.0, 0.0], 3, 0.1));
+        // too few members to be a group
+        positions.extend(lattice([0.0, 10.0, 0.0], 2, 0.1));
+        let particles = particles(positions);
+        let groups = find_groups_with_length(&particles, 0.15, 20);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].members, (0..64).collect::<Vec<_>>());
+        assert_eq!(groups[1].members, (64..91).collect::<Vec<_>>());
+        assert_eq!(groups[0].mass, 64.0);
+        for k in 0..3 {
+            assert!(groups[0].center_of_mass[k].abs() < 1e-5);
+        }
+        assert!((groups[1].center_of_mass[0] - 10.1).abs() < 1e-5);
+        assert!((groups[0].radius - 0.15 * 3f32.sqrt()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn chain_across_cells() {
+        // one particle per grid cell, so every link is between neighbouring cells, and a last
+        // particle just out of reach
+        let mut positions: Vec<[f32; 3]> =
+            (0..10).map(|k| [0.5 + 0.99 * k as f32, 0.5, 0.5]).collect();
+        positions.push([0.5 + 0.99 * 9.0 + 1.01, 0.5, 0.5]);
+        let particles = particles(positions);
+        let groups = find_groups_with_length(&particles, 1.0, 2);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].members, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn coincident_points() {
+        let mut positions = vec![[0.3, -0.2, 0.1]; 5];
+        positions.push([5.0, 0.0, 0.0]);
+        let particles = particles(positions);
+        let groups = find_groups_with_length(&particles, 0.01, 1);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].members, vec![0, 1, 2, 3, 4]);
+        for (x, expected) in groups[0].center_of_mass.into_iter().zip([0.3, -0.2, 0.1]) {
+            assert!((x - expected).abs() < 1e-6);
+        }
+        assert!(groups[0].radius < 1e-6);
+        assert_eq!(groups[1].members, vec![5]);
+    }
+}
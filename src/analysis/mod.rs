@@ -0,0 +1,7 @@
+pub mod accuracy;
+pub mod correlation;
+pub mod energy;
+pub mod fof;
+pub mod orbits;
+pub mod power_spectrum;
+pub mod profiles;
@@ -0,0 +1,499 @@
+use std::{
+    collections::{HashMap, HashSet},
+    f64::consts::TAU,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use glam::DVec3;
+use log::info;
+use rayon::prelude::*;
+
+use crate::sims::{distance, Octree, Particle};
+
+/// Osculating Keplerian elements of a body relative to a central body. Angles are in radians,
+/// the reference plane is xy and the reference direction +x.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OrbitalElements {
+    /// Negative for unbound (hyperbolic) orbits, infinite for parabolic ones
+    pub semi_major_axis: f64,
+    pub eccentricity: f64,
+    pub inclination: f64,
+    /// Longitude of the ascending node, zero for orbits in the reference plane
+    pub ascending_node: f64,
+    /// Argument of periapsis, measured from the x axis for orbits in the reference plane
+    pub argument_of_periapsis: f64,
+    /// Mean anomaly, the hyperbolic mean anomaly for unbound orbits
+    pub mean_anomaly: f64,
+}
+
+impl OrbitalElements {
+    /// Elements of the orbit with relative position `pos` and velocity `vel` around a mass with
+    /// gravitational parameter `mu` (`g * (central mass + body mass)`).
+    pub fn from_state(pos: DVec3, vel: DVec3, mu: f64) -> Self {
+        const EPSILON: f64 = 1e-10;
+        let r = pos.length();
+        let h = pos.cross(vel);
+        // points at the ascending node
+        let node = DVec3::new(-h.y, h.x, 0.0);
+        let e_vec = ((vel.length_squared() - mu / r) * pos - pos.dot(vel) * vel) / mu;
+        let eccentricity = e_vec.length();
+        let energy = vel.length_squared() / 2.0 - mu / r;
+        let semi_major_axis = -mu / (2.0 * energy);
+        let inclination = (h.z / h.length()).clamp(-1.0, 1.0).acos();
+
+        let equatorial = node.length() < EPSILON * h.length();
+        let circular = eccentricity < EPSILON;
+        let ascending_node = match equatorial {
+            true => 0.0,
+            false => node.y.atan2(node.x).rem_euclid(TAU),
+        };
+        // angle of `v` from `from` in the orbital plane
+        let angle = |from: DVec3, v: DVec3| from.cross(v).dot(h).atan2(from.dot(v) * h.length());
+        let reference = match equatorial {
+            true => DVec3::X,
+            false => node,
+        };
+        let argument_of_periapsis = match circular {
+            true => 0.0,
+            false => angle(reference, e_vec).rem_euclid(TAU),
+        };
+        // circular orbits measure the anomaly from the node (or x axis) instead of periapsis
+        let true_anomaly = match circular {
+            true => angle(reference, pos),
+            false => angle(e_vec, pos),
+        };
+
+        let half_tan = (true_anomaly / 2.0).tan();
+        let mean_anomaly = if eccentricity < 1.0 - EPSILON {
+            let ecc_anomaly =
+                2.0 * (((1.0 - eccentricity) / (1.0 + eccentricity)).sqrt() * half_tan).atan();
+            (ecc_anomaly - eccentricity * ecc_anomaly.sin()).rem_euclid(TAU)
+        } else if eccentricity > 1.0 + EPSILON {
+            let hyp_anomaly =
+                2.0 * (((eccentricity - 1.0) / (eccentricity + 1.0)).sqrt() * half_tan).atanh();
+            eccentricity * hyp_anomaly.sinh() - hyp_anomaly
+        } else {
+            // Barker's equation
+            half_tan + half_tan.powi(3) / 3.0
+        };
+
+        OrbitalElements {
+            semi_major_axis,
+            eccentricity,
+            inclination,
+            ascending_node,
+            argument_of_periapsis,
+            mean_anomaly,
+        }
+    }
+
+    /// Closest distance to the central body.
+    pub fn periapsis(&self) -> f64 {
+        self.semi_major_axis * (1.0 - self.eccentricity)
+    }
+
+    /// Furthest distance from the central body, infinite for unbound orbits.
+    pub fn apoapsis(&self) -> f64 {
+        match self.eccentricity < 1.0 {
+            true => self.semi_major_axis * (1.0 + self.eccentricity),
+            false => f64::INFINITY,
+        }
+    }
+}
+
+/// Index of the most massive particle.
+pub fn most_massive(particles: &[Particle]) -> Option<usize> {
+    particles
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.mass.total_cmp(&b.mass))
+        .map(|(ix, _)| ix)
+}
+
+/// Elements of every particle but the central one (`central_id`, or the most massive when
+/// `None`) as `(id, elements)` pairs. Empty if the central body isn't found.
+pub fn orbital_elements(
+    particles: &[Particle],
+    central_id: Option<u32>,
+    g: f32,
+) -> Vec<(u32, OrbitalElements)> {
+    let central = match central_id {
+        Some(id) => particles.iter().position(|p| p.id == id),
+        None => most_massive(particles),
+    };
+    let central = match central {
+        Some(ix) => particles[ix],
+        None => return Vec::new(),
+    };
+    particles
+        .iter()
+        .filter(|p| p.id != central.id)
+        .map(|p| {
+            let pos = DVec3::from(p.position.map(f64::from))
+                - DVec3::from(central.position.map(f64::from));
+            let vel = DVec3::from(p.velocity.map(f64::from))
+                - DVec3::from(central.velocity.map(f64::from));
+            let mu = g as f64 * (central.mass as f64 + p.mass as f64);
+            (p.id, OrbitalElements::from_state(pos, vel, mu))
+        })
+        .collect()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OrbitEventKind {
+    /// `first` became unbound beyond the ejection radius
+    Ejection,
+    /// The periapsis to apoapsis ranges of `first` and `second` started overlapping
+    OrbitCrossing,
+    /// `first` and `second` came closer than the encounter radius
+    CloseEncounter { distance: f32 },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OrbitEvent {
+    pub time: f64,
+    pub kind: OrbitEventKind,
+    pub first: u32,
+    /// Unused (equal to `first`) for ejections
+    pub second: u32,
+}
+
+/// Records orbital element time series of a planetary system to a CSV file (one row per body
+/// and frame) and detects ejections, orbit crossings and close encounters between frames. Each
+/// event is logged and kept in `events` once, when it starts.
+pub struct OrbitTracker {
+    out: BufWriter<File>,
+    /// ID of the central body, the most massive body when `None`
+    pub central_id: Option<u32>,
+    /// Unbound bodies further than this from the central body count as ejected
+    pub ejection_radius: f32,
+    /// Bodies closer than this to each other are having a close encounter
+    pub encounter_radius: f32,
+    ejected: HashSet<u32>,
+    /// Pairs whose orbits overlapped at the last frame, sorted
+    crossing: Vec<(u32, u32)>,
+    /// Pairs that were closer than the encounter radius at the last frame, sorted
+    encountering: Vec<(u32, u32)>,
+    events: Vec<OrbitEvent>,
+}
+
+impl OrbitTracker {
+    pub fn create(
+        path: impl AsRef<Path>,
+        ejection_radius: f32,
+        encounter_radius: f32,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create orbit file {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        writeln!(
+            out,
+            "time,id,semi_major_axis,eccentricity,inclination,ascending_node,\
+             argument_of_periapsis,mean_anomaly"
+        )?;
+        Ok(Self {
+            out,
+            central_id: None,
+            ejection_radius,
+            encounter_radius,
+            ejected: HashSet::new(),
+            crossing: Vec::new(),
+            encountering: Vec::new(),
+            events: Vec::new(),
+        })
+    }
+
+    /// Appends the elements of every body at `time` and checks for new events. Tracers are
+    /// included, ejected bodies keep being written.
+    pub fn record(&mut self, time: f64, particles: &[Particle], g: f32) -> anyhow::Result<()> {
+        let elements = orbital_elements(particles, self.central_id, g);
+        for (id, el) in &elements {
+            writeln!(
+                self.out,
+                "{},{},{},{},{},{},{},{}",
+                time,
+                id,
+                el.semi_major_axis,
+                el.eccentricity,
+                el.inclination,
+                el.ascending_node,
+                el.argument_of_periapsis,
+                el.mean_anomaly
+            )?;
+        }
+
+        let index_of: HashMap<u32, usize> = particles
+            .iter()
+            .enumerate()
+            .map(|(ix, p)| (p.id, ix))
+            .collect();
+        let central = match self.central_id {
+            Some(id) => index_of.get(&id).map(|&ix| &particles[ix]),
+            None => most_massive(particles).map(|ix| &particles[ix]),
+        };
+        let central = match central {
+            Some(p) => *p,
+            None => return Ok(()),
+        };
+        for (id, el) in &elements {
+            let p = &particles[index_of[id]];
+            if el.eccentricity >= 1.0
+                && distance(&p.position, &central.position) > self.ejection_radius
+                && self.ejected.insert(*id)
+            {
+                self.push_event(time, OrbitEventKind::Ejection, *id, *id);
+            }
+        }
+
+        // sweep over the bound orbits by periapsis, each one overlaps the following ones up to
+        // the first that starts beyond its apoapsis
+        let mut ranges: Vec<(f64, f64, u32)> = elements
+            .iter()
+            .filter(|(_, el)| el.eccentricity < 1.0)
+            .map(|(id, el)| (el.periapsis(), el.apoapsis(), *id))
+            .collect();
+        ranges.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+        let mut crossing = Vec::new();
+        for (i, &(_, apo_a, id_a)) in ranges.iter().enumerate() {
+            for &(_, _, id_b) in ranges[i + 1..].iter().take_while(|b| b.0 <= apo_a) {
+                crossing.push(pair(id_a, id_b));
+            }
+        }
+        crossing.sort_unstable();
+        for &(a, b) in &crossing {
+            if self.crossing.binary_search(&(a, b)).is_err() {
+                self.push_event(time, OrbitEventKind::OrbitCrossing, a, b);
+            }
+        }
+        self.crossing = crossing;
+
+        // tracers can have encounters too, so every body goes into the tree with unit mass
+        let bodies: Vec<Particle> = particles
+            .iter()
+            .map(|p| Particle { mass: 1.0, ..*p })
+            .collect();
+        let octree = Octree::build(&bodies);
+        let radius = self.encounter_radius;
+        let mut encountering: Vec<((u32, u32), f32)> = (0..particles.len() as u32)
+            .into_par_iter()
+            .flat_map_iter(|a| {
+                let p = &particles[a as usize];
+                octree
+                    .within_radius(p.position, radius)
+                    .into_iter()
+                    .filter(move |&b| b > a)
+                    .map(move |b| {
+                        let q = &particles[b as usize];
+                        (pair(p.id, q.id), distance(&p.position, &q.position))
+                    })
+                    .filter(move |&(_, distance)| distance < radius)
+            })
+            .collect();
+        encountering.sort_unstable_by_key(|&(key, _)| key);
+        for &(key, distance) in &encountering {
+            if self.encountering.binary_search(&key).is_err() {
+                let kind = OrbitEventKind::CloseEncounter { distance };
+                self.push_event(time, kind, key.0, key.1);
+            }
+        }
+        self.encountering = encountering.into_iter().map(|(key, _)| key).collect();
+        Ok(())
+    }
+
+    /// Every event detected so far, in the order they were found.
+    pub fn events(&self) -> &[OrbitEvent] {
+        &self.events
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.out.flush().context("Failed to flush orbit file")
+    }
+
+    fn push_event(&mut self, time: f64, kind: OrbitEventKind, first: u32, second: u32) {
+        match kind {
+            OrbitEventKind::Ejection => info!("t = {}: body {} was ejected", time, first),
+            OrbitEventKind::OrbitCrossing => {
+                info!("t = {}: orbits of {} and {} cross", time, first, second)
+            }
+            OrbitEventKind::CloseEncounter { distance } => info!(
+                "t = {}: close encounter between {} and {} at distance {}",
+                time, first, second, distance
+            ),
+        }
+        self.events.push(OrbitEvent {
+            time,
+            kind,
+            first,
+            second,
+        });
+    }
+}
+
+/// Unordered pair key.
+fn pair(a: u32, b: u32) -> (u32, u32) {
+    (a.min(b), a.max(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::Zeroable;
+
+    use super::*;
+
+    const TOLERANCE: f64 = 1e-9;
+
+    fn body(id: u32, mass: f32, position: [f32; 3], velocity: [f32; 3]) -> Particle {
+        Particle {
+            position,
+            velocity,
+            mass,
+            id,
+            ..Zeroable::zeroed()
+        }
+    }
+
+    #[test]
+    fn circular_orbit() {
+        let el =
+            OrbitalElements::from_state(DVec3::new(0.0, 2.0, 0.0), DVec3::new(-0.5, 0.0, 0.0), 0.5);
+        assert!((el.semi_major_axis - 2.0).abs() < TOLERANCE);
+        assert!(el.eccentricity < TOLERANCE);
+        assert!(el.inclination.abs() < TOLERANCE);
+        assert_eq!(el.ascending_node, 0.0);
+        assert_eq!(el.argument_of_periapsis, 0.0);
+        // measured from the x axis for circular equatorial orbits
+        assert!((el.mean_anomaly - TAU / 4.0).abs() < TOLERANCE);
+        assert!((el.periapsis() - 2.0).abs() < TOLERANCE);
+        assert!((el.apoapsis() - 2.0).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn eccentric_inclined_orbit() {
+        let (a, e, i, node, peri, true_anomaly, mu): (f64, f64, f64, f64, f64, f64, f64) =
+            (2.0, 0.5, 0.3, 1.0, 0.5, 0.7, 3.0);
+        // state in the perifocal frame, rotated by the argument of periapsis, the inclination
+        // and the ascending node
+        let p = a * (1.0 - e * e);
+        let r = p / (1.0 + e * true_anomaly.cos());
+        let pos = r * DVec3::new(true_anomaly.cos(), true_anomaly.sin(), 0.0);
+        let vel = (mu / p).sqrt() * DVec3::new(-true_anomaly.sin(), e + true_anomaly.cos(), 0.0);
+        let rotation = glam::DQuat::from_rotation_z(node)
+            * glam::DQuat::from_rotation_x(i)
+            * glam::DQuat::from_rotation_z(peri);
+        let el = OrbitalElements::from_state(rotation * pos, rotation * vel, mu);
+
+        let ecc_anomaly =
+            2.0 * (((1.0 - e) / (1.0 + e)).sqrt() * (true_anomaly / 2.0).tan()).atan();
+        let mean_anomaly = ecc_anomaly - e * ecc_anomaly.sin();
+        assert!((el.semi_major_axis - a).abs() < TOLERANCE);
+        assert!((el.eccentricity - e).abs() < TOLERANCE);
+        assert!((el.inclination - i).abs() < TOLERANCE);
+        assert!((el.ascending_node - node).abs() < TOLERANCE);
+        assert!((el.argument_of_periapsis - peri).abs() < TOLERANCE);
+        assert!((el.mean_anomaly - mean_anomaly).abs() < TOLERANCE);
+        assert!((el.periapsis() - 1.0).abs() < TOLERANCE);
+        assert!((el.apoapsis() - 3.0).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn ejection() {
+        // twice the circular speed, well above escape speed
+        let el = OrbitalElements::from_state(DVec3::X, DVec3::new(0.0, 2.0, 0.0), 1.0);
+        assert!((el.eccentricity - 3.0).abs() < TOLERANCE);
+        assert!((el.semi_major_axis + 0.5).abs() < TOLERANCE);
+        assert_eq!(el.apoapsis(), f64::INFINITY);
+
+        let path = std::env::temp_dir().join(format!("orbits-ejection-{}.csv", std::process::id()));
+        let mut tracker = OrbitTracker::create(&path, 5.0, 0.0).unwrap();
+        let mut particles = vec![
+            body(7, 1.0, [0.0; 3], [0.0; 3]),
+            body(3, 0.0, [1.0, 0.0, 0.0], [0.0, 2.0, 0.0]),
+        ];
+        tracker.record(0.0, &particles, 1.0).unwrap();
+        assert!(
+            tracker.events().is_empty(),
+            "unbound but inside the ejection radius"
+        );
+        particles[1].position = [10.0, 0.0, 0.0];
+        tracker.record(1.0, &particles, 1.0).unwrap();
+        tracker.record(2.0, &particles, 1.0).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            tracker.events(),
+            &[OrbitEvent {
+                time: 1.0,
+                kind: OrbitEventKind::Ejection,
+                first: 3,
+                second: 3,
+            }]
+        );
+    }
+
+    #[test]
+    fn orbit_crossings() {
+        let path = std::env::temp_dir().join(format!("orbits-crossing-{}.csv", std::process::id()));
+        let mut tracker = OrbitTracker::create(&path, 100.0, 0.0).unwrap();
+        // circular orbits at radius 1, 2 and 4, only the second is crossed by an eccentric one
+        // from 1.5 to 2.5
+        let circular = |id, r: f32| body(id, 0.0, [r, 0.0, 0.0], [0.0, r.powf(-0.5), 0.0]);
+        let particles = vec![
+            body(0, 1.0, [0.0; 3], [0.0; 3]),
+            circular(1, 1.0),
+            circular(2, 2.0),
+            circular(3, 4.0),
+            body(4, 0.0, [1.5, 0.0, 0.0], [0.0, (2.5f32 / 3.0).sqrt(), 0.0]),
+        ];
+        tracker.record(0.0, &particles, 1.0).unwrap();
+        tracker.record(1.0, &particles, 1.0).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            tracker.events(),
+            &[OrbitEvent {
+                time: 0.0,
+                kind: OrbitEventKind::OrbitCrossing,
+                first: 2,
+                second: 4,
+            }]
+        );
+    }
+
+    #[test]
+    fn close_encounters() {
+        let path =
+            std::env::temp_dir().join(format!("orbits-encounter-{}.csv", std::process::id()));
+        let mut tracker = OrbitTracker::create(&path, 100.0, 0.1).unwrap();
+        let mut particles = vec![
+            body(0, 1.0, [0.0; 3], [0.0; 3]),
+            body(5, 0.0, [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+            body(2, 0.0, [1.0, 0.05, 0.0], [0.0, 1.0, 0.0]),
+        ];
+        let times = [0.0, 1.0, 2.0, 3.0];
+        for (time, y) in times.into_iter().zip([0.05, 0.06, 0.5, 0.08]) {
+            particles[2].position[1] = y;
+            tracker.record(time, &particles, 1.0).unwrap();
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        let encounters: Vec<(f64, u32, u32, f32)> = tracker
+            .events()
+            .iter()
+            .filter_map(|e| match e.kind {
+                OrbitEventKind::CloseEncounter { distance } => {
+                    Some((e.time, e.first, e.second, distance))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(encounters.len(), 2);
+        for (&(time, first, second, distance), (expected_time, expected_distance)) in
+            encounters.iter().zip([(0.0, 0.05), (3.0, 0.08)])
+        {
+            assert_eq!((time, first, second), (expected_time, 2, 5));
+            assert!((distance - expected_distance).abs() < 1e-6);
+        }
+    }
+}
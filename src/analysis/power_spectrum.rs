@@ -0,0 +1,331 @@
+use std::{
+    f64::consts::{PI, TAU},
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{ensure, Context};
+use glam::DVec2;
+use rayon::prelude::*;
+
+use crate::sims::Particle;
+
+/// A logarithmic wavenumber bin of a power spectrum.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PowerBin {
+    pub k_inner: f32,
+    pub k_outer: f32,
+    /// Mean wavenumber of the modes in the bin
+    pub k: f32,
+    /// Mean corrected power, in units of volume
+    pub power: f32,
+    pub modes: u32,
+}
+
+/// Matter power spectrum of the massive particles (mass weighted), using cloud-in-cell
+/// assignment to a periodic `grid`^3 mesh. `box_region` is the cube's lower corner and side,
+/// particles outside are wrapped into it. Without a box the bounding cube of the particles is
+/// used, which treats an isolated system as periodic.
+///
+/// The squared CIC window is divided out and the shot noise (`V sum m^2 / (sum m)^2`) subtracted,
+/// with the aliased shot noise term approximated by
+/// `prod(1 - 2/3 sin^2(pi k_i / (2 k_N)))` (Jing 2005). Modes between the fundamental and the
+/// Nyquist wavenumber are averaged in `bins` logarithmic bins.
+pub fn power_spectrum(
+    particles: &[Particle],
+    grid: usize,
+    box_region: Option<([f32; 3], f32)>,
+    bins: usize,
+) -> anyhow::Result<Vec<PowerBin>> {
+    ensure!(
+        grid >= 2 && grid.is_power_of_two(),
+        "power spectrum grid size must be a power of two"
+    );
+    ensure!(bins > 0, "power spectrum needs at least one bin");
+    let massive: Vec<&Particle> = particles.iter().filter(|p| !p.is_tracer()).collect();
+    if massive.is_empty() {
+        return Ok(Vec::new());
+    }
+    let (origin, box_size) = match box_region {
+        Some((origin, box_size)) => {
+            ensure!(box_size > 0.0, "power spectrum box size must be positive");
+            (origin.map(f64::from), box_size as f64)
+        }
+        None => bounding_cube(&massive),
+    };
+
+    let n = grid;
+    let cell = box_size / n as f64;
+    let mut density = vec![0.0f64; n * n * n];
+    let mut total_mass = 0.0;
+    let mut total_mass2 = 0.0;
+    for p in &massive {
+        let m = p.mass as f64;
+        total_mass += m;
+        total_mass2 += m * m;
+        // cell centres sit at (i + 0.5) * cell
+        let mut lower = [0usize; 3];
+        let mut frac = [0.0; 3];
+        for k in 0..3 {
+            let u = (p.position[k] as f64 - origin[k]) / cell - 0.5;
+            let floor = u.floor();
+            frac[k] = u - floor;
+            lower[k] = (floor as i64).rem_euclid(n as i64) as usize;
+        }
+        for corner in 0..8 {
+            let mut weight = m;
+            let mut ix = 0;
+            for k in 0..3 {
+                let upper = (corner >> k) & 1 == 1;
+                weight *= match upper {
+                    true => frac[k],
+                    false => 1.0 - frac[k],
+                };
+                ix = ix * n + (lower[k] + upper as usize) % n;
+            }
+            density[ix] += weight;
+        }
+    }
+
+    // density contrast, transformed in place
+    let mean = total_mass / (n * n * n) as f64;
+    let mut modes: Vec<DVec2> = density
+        .par_iter()
+        .map(|&rho| DVec2::new(rho / mean - 1.0, 0.0))
+        .collect();
+    drop(density);
+    fft_3d(&mut modes, n);
+
+    let volume = box_size.powi(3);
+    let shot_noise = volume * total_mass2 / (total_mass * total_mass);
+    let normalization = volume / ((n * n * n) as f64).powi(2);
+    let fundamental = TAU / box_size;
+    let nyquist = PI * n as f64 / box_size;
+    let log_min = fundamental.ln();
+    let log_step = (nyquist.ln() - log_min) / bins as f64;
+    let edge = |ix: usize| (log_min + log_step * ix as f64).exp();
+
+    // (sum k, sum power, modes) per bin
+    let sums = modes
+        .par_iter()
+        .enumerate()
+        .fold(
+            || vec![(0.0, 0.0, 0u32); bins],
+            |mut sums, (ix, delta)| {
+                let wave = [ix / (n * n), ix / n % n, ix % n].map(|i| {
+                    // negative frequencies are stored in the upper half
+                    let signed = match i <= n / 2 {
+                        true => i as f64,
+                        false => i as f64 - n as f64,
+                    };
+                    signed * fundamental
+                });
+                let k = (wave[0] * wave[0] + wave[1] * wave[1] + wave[2] * wave[2]).sqrt();
+                if k == 0.0 {
+                    return sums;
+                }
+                let bin = ((k.ln() - log_min) / log_step) as usize;
+                if k > nyquist || bin >= bins {
+                    return sums;
+                }
+                let mut window = 1.0;
+                let mut aliased_noise = 1.0;
+                for k_i in wave {
+                    let x = PI * k_i / (2.0 * nyquist);
+                    let sinc = match x == 0.0 {
+                        true => 1.0,
+                        false => x.sin() / x,
+                    };
+                    window *= sinc * sinc;
+                    aliased_noise *= 1.0 - 2.0 / 3.0 * x.sin().powi(2);
+                }
+                let raw = normalization * delta.length_squared();
+                let power = (raw - shot_noise * aliased_noise) / (window * window);
+                let sum = &mut sums[bin];
+                sum.0 += k;
+                sum.1 += power;
+                sum.2 += 1;
+                sums
+            },
+        )
+        .reduce(
+            || vec![(0.0, 0.0, 0u32); bins],
+            |mut a, b| {
+                for (a, b) in a.iter_mut().zip(b) {
+                    a.0 += b.0;
+                    a.1 += b.1;
+                    a.2 += b.2;
+                }
+                a
+            },
+        );
+
+    Ok(sums
+        .iter()
+        .enumerate()
+        .map(|(ix, &(k, power, modes))| {
+            let count = modes.max(1) as f64;
+            PowerBin {
+                k_inner: edge(ix) as f32,
+                k_outer: edge(ix + 1) as f32,
+                k: match modes {
+                    0 => ((edge(ix) * edge(ix + 1)).sqrt()) as f32,
+                    _ => (k / count) as f32,
+                },
+                power: match modes {
+                    0 => f32::NAN,
+                    _ => (power / count) as f32,
+                },
+                modes,
+            }
+        })
+        .collect())
+}
+
+/// Writes one row per bin.
+pub fn write_power_spectrum(path: impl AsRef<Path>, bins: &[PowerBin]) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create power spectrum file {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    writeln!(out, "k_inner,k_outer,k,power,modes")?;
+    for b in bins {
+        writeln!(
+            out,
+            "{},{},{},{},{}",
+            b.k_inner, b.k_outer, b.k, b.power, b.modes
+        )?;
+    }
+    out.flush().context("Failed to flush power spectrum file")
+}
+
+/// Lower corner and side of the smallest cube around the particles, padded slightly so the
+/// furthest particles don't wrap around onto the first cells.
+fn bounding_cube(particles: &[&Particle]) -> ([f64; 3], f64) {
+    let mut min = [f64::INFINITY; 3];
+    let mut max = [f64::NEG_INFINITY; 3];
+    for p in particles {
+        for k in 0..3 {
+            min[k] = min[k].min(p.position[k] as f64);
+            max[k] = max[k].max(p.position[k] as f64);
+        }
+    }
+    let side = (0..3).map(|k| max[k] - min[k]).fold(0.0, f64::max);
+    let side = match side > 0.0 {
+        true => side * (1.0 + 1e-6),
+        false => 1.0,
+    };
+    (min, side)
+}
+
+/// Forward transform of an `n`^3 row-major grid, one axis at a time.
+fn fft_3d(data: &mut [DVec2], n: usize) {
+    // last axis is contiguous
+    data.par_chunks_mut(n).for_each(fft);
+    for stride in [n, n * n] {
+        let base = |line: usize| line / stride * stride * n + line % stride;
+        let lines: Vec<Vec<DVec2>> = (0..n * n)
+            .into_par_iter()
+            .map(|line| {
+                let mut values: Vec<DVec2> =
+                    (0..n).map(|i| data[base(line) + i * stride]).collect();
+                fft(&mut values);
+                values
+            })
+            .collect();
+        for (line, values) in lines.into_iter().enumerate() {
+            for (i, value) in values.into_iter().enumerate() {
+                data[base(line) + i * stride] = value;
+            }
+        }
+    }
+}
+
+/// In-place iterative radix-2 forward FFT of a power of two length slice.
+fn fft(data: &mut [DVec2]) {
+    let n = data.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -TAU / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let b = data[start + k + len / 2];
+                let b = DVec2::new(b.x * cos - b.y * sin, b.x * sin + b.y * cos);
+                let a = data[start + k];
+                data[start + k] = a + b;
+                data[start + k + len / 2] = a - b;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::Zeroable;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn fft_matches_dft() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let input: Vec<DVec2> = (0..64)
+            .map(|_| DVec2::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)))
+            .collect();
+        let mut output = input.clone();
+        fft(&mut output);
+        let n = input.len();
+        for (k, out) in output.iter().enumerate() {
+            let expected = input
+                .iter()
+                .enumerate()
+                .map(|(j, x)| {
+                    let (sin, cos) = (-TAU * (j * k % n) as f64 / n as f64).sin_cos();
+                    DVec2::new(x.x * cos - x.y * sin, x.x * sin + x.y * cos)
+                })
+                .fold(DVec2::ZERO, |a, b| a + b);
+            assert!((*out - expected).length() < 1e-10, "mode {}", k);
+        }
+    }
+
+    #[test]
+    fn poisson_power_is_shot_noise() {
+        const N: usize = 200_000;
+        let mut rng = StdRng::seed_from_u64(2);
+        let particles: Vec<Particle> = (0..N)
+            .map(|ix| Particle {
+                position: [0; 3].map(|_| rng.gen_range(0.0..1.0)),
+                mass: 1.0,
+                id: ix as u32,
+                ..Zeroable::zeroed()
+            })
+            .collect();
+        let bins = power_spectrum(&particles, 32, Some(([0.0; 3], 1.0)), 8).unwrap();
+        // the raw power of every mode scatters around the shot noise, so bin means of the
+        // corrected power scatter around zero by about shot noise / sqrt(modes)
+        let shot_noise = 1.0 / N as f32;
+        assert!(bins.iter().all(|bin| bin.modes > 0));
+        for bin in &bins {
+            assert!(
+                bin.power.abs() * (bin.modes as f32).sqrt() < 4.0 * shot_noise,
+                "{:?}",
+                bin
+            );
+        }
+    }
+}
@@ -0,0 +1,434 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{ensure, Context};
+use glam::DVec3;
+
+use crate::sims::Particle;
+
+/// Point profiles are measured around. Only massive particles are considered.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ProfileCenter {
+    CenterOfMass,
+    /// Found with shrinking spheres: the sphere around the centre of mass of the particles inside
+    /// it shrinks by 2.5% per iteration until fewer than 1% (at least 100) of them are left
+    DensityPeak,
+    /// Position of the particle with this ID
+    Particle(u32),
+    Point([f32; 3]),
+}
+
+/// A spherical shell of a radial profile.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RadialBin {
+    pub r_inner: f32,
+    pub r_outer: f32,
+    pub count: u32,
+    /// Shell mass over shell volume
+    pub density: f32,
+    /// Mass inside `r_outer`, including everything inside the innermost bin
+    pub enclosed_mass: f32,
+    /// `sqrt(g * enclosed_mass / r_outer)`
+    pub circular_velocity: f32,
+    /// Mass weighted one-dimensional dispersion `sqrt((sigma_r^2 + sigma_t^2) / 3)`
+    pub velocity_dispersion: f32,
+    pub sigma_r: f32,
+    /// Combined dispersion of both tangential directions
+    pub sigma_t: f32,
+    /// `1 - sigma_t^2 / (2 sigma_r^2)`, zero for isotropic orbits
+    pub anisotropy: f32,
+}
+
+/// Position and bulk velocity of `center`, the velocity being the mass weighted mean of all
+/// massive particles. `None` if there are none or the particle doesn't exist.
+pub fn find_center(particles: &[Particle], center: ProfileCenter) -> Option<([f32; 3], [f32; 3])> {
+    let massive: Vec<&Particle> = particles.iter().filter(|p| !p.is_tracer()).collect();
+    let (position, velocity) = mass_weighted_mean(&massive)?;
+    let position = match center {
+        ProfileCenter::CenterOfMass => position,
+        ProfileCenter::DensityPeak => {
+            let mut inside = massive.clone();
+            let mut center = position;
+            let mut radius = inside
+                .iter()
+                .map(|p| (pos(p) - center).length())
+                .fold(0.0, f64::max);
+            let target = (massive.len() / 100).max(100);
+            while inside.len() > target {
+                radius *= 0.975;
+                let shrunk: Vec<&Particle> = inside
+                    .iter()
+                    .copied()
+                    .filter(|p| (pos(p) - center).length() <= radius)
+                    .collect();
+                if shrunk.is_empty() {
+                    break;
+                }
+                inside = shrunk;
+                center = mass_weighted_mean(&inside)?.0;
+            }
+            center
+        }
+        ProfileCenter::Particle(id) => pos(particles.iter().find(|p| p.id == id)?),
+        ProfileCenter::Point(point) => DVec3::from(point.map(f64::from)),
+    };
+    Some((position.as_vec3().to_array(), velocity.as_vec3().to_array()))
+}
+
+/// Profile in `bins` logarithmically spaced shells between `r_min` and `r_max`. Velocities are
+/// relative to the bulk velocity of all massive particles.
+pub fn radial_profile(
+    particles: &[Particle],
+    center: ProfileCenter,
+    r_min: f32,
+    r_max: f32,
+    bins: usize,
+    g: f32,
+) -> anyhow::Result<Vec<RadialBin>> {
+    ensure!(
+        0.0 < r_min && r_min < r_max && bins > 0,
+        "radial bins need 0 < r_min < r_max and at least one bin"
+    );
+    let (center, bulk) = match find_center(particles, center) {
+        Some((center, bulk)) => (
+            DVec3::from(center.map(f64::from)),
+            DVec3::from(bulk.map(f64::from)),
+        ),
+        None => return Ok(Vec::new()),
+    };
+    let log_min = (r_min as f64).ln();
+    let log_step = ((r_max as f64).ln() - log_min) / bins as f64;
+    let edge = |ix: usize| (log_min + log_step * ix as f64).exp();
+
+    #[derive(Copy, Clone, Default)]
+    struct Shell {
+        count: u32,
+        mass: f64,
+        // mass weighted sums of the radial velocity, its square and the tangential speed squared
+        vr: f64,
+        vr2: f64,
+        vt2: f64,
+        // mass weighted sum of the tangential velocity vectors
+        vt: DVec3,
+    }
+    let mut shells = vec![Shell::default(); bins];
+    let mut inner_mass = 0.0;
+    for p in particles.iter().filter(|p| !p.is_tracer()) {
+        let offset = pos(p) - center;
+        let r = offset.length();
+        if r < r_min as f64 {
+            inner_mass += p.mass as f64;
+            continue;
+        }
+        let ix = ((r.ln() - log_min) / log_step) as usize;
+        if ix >= bins {
+            continue;
+        }
+        let m = p.mass as f64;
+        let vel = DVec3::from(p.velocity.map(f64::from)) - bulk;
+        let vr = vel.dot(offset / r);
+        let vt = vel - vr * offset / r;
+        let shell = &mut shells[ix];
+        shell.count += 1;
+        shell.mass += m;
+        shell.vr += m * vr;
+        shell.vr2 += m * vr * vr;
+        shell.vt2 += m * vt.length_squared();
+        shell.vt += m * vt;
+    }
+
+    let mut enclosed = inner_mass;
+    Ok(shells
+        .iter()
+        .enumerate()
+        .map(|(ix, shell)| {
+            let (r_inner, r_outer) = (edge(ix), edge(ix + 1));
+            enclosed += shell.mass;
+            let volume = 4.0 / 3.0 * std::f64::consts::PI * (r_outer.powi(3) - r_inner.powi(3));
+            let (sigma_r2, sigma_t2) = match shell.mass > 0.0 {
+                true => {
+                    let mean_vr = shell.vr / shell.mass;
+                    let mean_vt = shell.vt / shell.mass;
+                    (
+                        (shell.vr2 / shell.mass - mean_vr * mean_vr).max(0.0),
+                        (shell.vt2 / shell.mass - mean_vt.length_squared()).max(0.0),
+                    )
+                }
+                false => (0.0, 0.0),
+            };
+            RadialBin {
+                r_inner: r_inner as f32,
+                r_outer: r_outer as f32,
+                count: shell.count,
+                density: (shell.mass / volume) as f32,
+                enclosed_mass: enclosed as f32,
+                circular_velocity: (g as f64 * enclosed / r_outer).sqrt() as f32,
+                velocity_dispersion: ((sigma_r2 + sigma_t2) / 3.0).sqrt() as f32,
+                sigma_r: sigma_r2.sqrt() as f32,
+                sigma_t: sigma_t2.sqrt() as f32,
+                anisotropy: match sigma_r2 > 0.0 {
+                    true => (1.0 - sigma_t2 / (2.0 * sigma_r2)) as f32,
+                    false => 0.0,
+                },
+            }
+        })
+        .collect())
+}
+
+/// Writes one row per bin.
+pub fn write_profile(path: impl AsRef<Path>, bins: &[RadialBin]) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create profile file {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    writeln!(
+        out,
+        "r_inner,r_outer,count,density,enclosed_mass,circular_velocity,velocity_dispersion,\
+         sigma_r,sigma_t,anisotropy"
+    )?;
+    for b in bins {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{},{}",
+            b.r_inner,
+            b.r_outer,
+            b.count,
+            b.density,
+            b.enclosed_mass,
+            b.circular_velocity,
+            b.velocity_dispersion,
+            b.sigma_r,
+            b.sigma_t,
+            b.anisotropy
+        )?;
+    }
+    out.flush().context("Failed to flush profile file")
+}
+
+/// Radii around `center` enclosing each of `fractions` (e.g. 0.5 for the half-mass radius) of
+/// the total massive particle mass. Empty if the centre isn't found.
+pub fn lagrangian_radii(
+    particles: &[Particle],
+    center: ProfileCenter,
+    fractions: &[f32],
+) -> Vec<f32> {
+    let center = match find_center(particles, center) {
+        Some((center, _)) => DVec3::from(center.map(f64::from)),
+        None => return Vec::new(),
+    };
+    let mut shells: Vec<(f64, f64)> = particles
+        .iter()
+        .filter(|p| !p.is_tracer())
+        .map(|p| ((pos(p) - center).length(), p.mass as f64))
+        .collect();
+    shells.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let total: f64 = shells.iter().map(|(_, m)| m).sum();
+    fractions
+        .iter()
+        .map(|&fraction| {
+            let target = fraction as f64 * total;
+            let mut enclosed = 0.0;
+            shells
+                .iter()
+                .find(|(_, m)| {
+                    enclosed += m;
+                    enclosed >= target
+                })
+                .or(shells.last())
+                .map_or(0.0, |(r, _)| *r as f32)
+        })
+        .collect()
+}
+
+/// Records Lagrangian radii over time to a CSV file with one row per frame.
+pub struct LagrangianRadiiWriter {
+    out: BufWriter<File>,
+    center: ProfileCenter,
+    fractions: Vec<f32>,
+}
+
+impl LagrangianRadiiWriter {
+    /// Tracks the radii enclosing `fractions` of the mass, conventionally `[0.1, 0.5, 0.9]`.
+    pub fn create(
+        path: impl AsRef<Path>,
+        center: ProfileCenter,
+        fractions: &[f32],
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path).with_context(|| {
+            format!("Failed to create Lagrangian radii file {}", path.display())
+        })?;
+        let mut out = BufWriter::new(file);
+        write!(out, "time")?;
+        for fraction in fractions {
+            write!(out, ",r_{}", fraction)?;
+        }
+        writeln!(out)?;
+        Ok(Self {
+            out,
+            center,
+            fractions: fractions.to_vec(),
+        })
+    }
+
+    /// Appends the radii at `time`, NaN if the centre isn't found.
+    pub fn record(&mut self, time: f64, particles: &[Particle]) -> anyhow::Result<()> {
+        let radii = lagrangian_radii(particles, self.center, &self.fractions);
+        write!(self.out, "{}", time)?;
+        for ix in 0..self.fractions.len() {
+            write!(self.out, ",{}", radii.get(ix).copied().unwrap_or(f32::NAN))?;
+        }
+        writeln!(self.out)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.out
+            .flush()
+            .context("Failed to flush Lagrangian radii file")
+    }
+}
+
+fn pos(p: &Particle) -> DVec3 {
+    DVec3::from(p.position.map(f64::from))
+}
+
+/// Mass weighted mean position and velocity, `None` without mass.
+fn mass_weighted_mean(particles: &[&Particle]) -> Option<(DVec3, DVec3)> {
+    let mut mass = 0.0;
+    let mut position = DVec3::ZERO;
+    let mut velocity = DVec3::ZERO;
+    for p in particles {
+        let m = p.mass as f64;
+        mass += m;
+        position += m * pos(p);
+        velocity += m * DVec3::from(p.velocity.map(f64::from));
+    }
+    match mass > 0.0 {
+        true => Some((position / mass, velocity / mass)),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::Zeroable;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    const RADIUS: f32 = 2.0;
+
+    /// `n` particles of total mass 1 uniformly filling a sphere of `RADIUS` around `center`,
+    /// all moving with `velocity`.
+    fn uniform_sphere(n: usize, center: [f32; 3], velocity: [f32; 3], seed: u64) -> Vec<Particle> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..n)
+            .map(|id| {
+                let offset = loop {
+                    let offset = [0; 3].map(|_| rng.gen_range(-RADIUS..RADIUS));
+                    if offset.iter().map(|x| x * x).sum::<f32>() <= RADIUS * RADIUS {
+                        break offset;
+                    }
+                };
+                Particle {
+                    position: [0, 1, 2].map(|k| center[k] + offset[k]),
+                    velocity,
+                    mass: 1.0 / n as f32,
+                    id: id as u32,
+                    ..Zeroable::zeroed()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn uniform_sphere_profile() {
+        let particles = uniform_sphere(100_000, [0.0; 3], [0.0; 3], 1);
+        let bins = radial_profile(
+            &particles,
+            ProfileCenter::Point([0.0; 3]),
+            0.5,
+            RADIUS,
+            6,
+            1.0,
+        )
+        .unwrap();
+        let density = 3.0 / (4.0 * std::f32::consts::PI * RADIUS.powi(3));
+        for bin in &bins {
+            // enclosed mass grows like r^3
+            let expected = (bin.r_outer / RADIUS).powi(3);
+            assert!(
+                (bin.enclosed_mass - expected).abs() < 0.01,
+                "enclosed mass {} at r = {}, expected {}",
+                bin.enclosed_mass,
+                bin.r_outer,
+                expected
+            );
+            assert!((bin.density / density - 1.0).abs() < 0.1);
+        }
+        assert!((bins.last().unwrap().enclosed_mass - 1.0).abs() < 1e-3);
+
+        let fractions = [0.1, 0.5, 0.9];
+        let radii = lagrangian_radii(&particles, ProfileCenter::Point([0.0; 3]), &fractions);
+        for (r, fraction) in radii.into_iter().zip(fractions) {
+            let expected = RADIUS * fraction.cbrt();
+            assert!(
+                (r / expected - 1.0).abs() < 0.01,
+                "r_{} = {}, expected {}",
+                fraction,
+                r,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn shifted_center() {
+        let shift = [5.0, -3.0, 2.0];
+        let velocity = [1.0, 2.0, 3.0];
+        let mut particles = uniform_sphere(20_000, shift, velocity, 2);
+        // a dense clump off the sphere's centre holding a fifth of the mass
+        let clump_center = [shift[0] + 0.5, shift[1], shift[2]];
+        let mut clump = uniform_sphere(5_000, clump_center, velocity, 3);
+        for (ix, p) in clump.iter_mut().enumerate() {
+            for (x, c) in p.position.iter_mut().zip(clump_center) {
+                *x = c + (*x - c) * 0.025;
+            }
+            p.mass = 0.25 / 5_000.0;
+            p.id = 20_000 + ix as u32;
+        }
+        for p in &mut particles {
+            p.mass = 1.0 / 20_000.0;
+        }
+        particles.extend(clump);
+        let close = |a: [f32; 3], b: [f32; 3], tolerance: f32| {
+            (0..3).all(|k| (a[k] - b[k]).abs() < tolerance)
+        };
+
+        let (center, bulk) = find_center(&particles, ProfileCenter::CenterOfMass).unwrap();
+        // the clump pulls the centre of mass a fifth of the way towards it
+        assert!(
+            close(center, [shift[0] + 0.1, shift[1], shift[2]], 0.02),
+            "{:?}",
+            center
+        );
+        assert!(close(bulk, velocity, 1e-5));
+        let (peak, _) = find_center(&particles, ProfileCenter::DensityPeak).unwrap();
+        assert!(close(peak, clump_center, 0.02), "{:?}", peak);
+        let (particle, _) = find_center(&particles, ProfileCenter::Particle(7)).unwrap();
+        assert_eq!(particle, particles[7].position);
+        assert_eq!(
+            find_center(&particles, ProfileCenter::Particle(u32::MAX)),
+            None
+        );
+
+        // Lagrangian radii around the sphere's centre don't depend on where it is
+        let sphere = &particles[..20_000];
+        let radii = lagrangian_radii(sphere, ProfileCenter::Point(shift), &[0.5]);
+        assert!((radii[0] / (RADIUS * 0.5f32.cbrt()) - 1.0).abs() < 0.01);
+    }
+}
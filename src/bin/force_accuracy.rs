@@ -0,0 +1,56 @@
+use wgpu_n_body::{
+    analysis::accuracy::{self, ForceReference},
+    inits,
+    sims::{Particle, SimParams},
+};
+
+#[global_allocator]
+static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+
+/// 99th percentile relative force error the suggested theta has to stay below.
+const MAX_P99: f64 = 0.01;
+
+type InitFn = fn(&SimParams) -> Vec<Particle>;
+
+fn main() {
+    env_logger::init();
+    let sim_params = SimParams {
+        particle_num: 20000,
+        g: 0.000000016,
+        e: 0.0001,
+        dt: 0.016,
+    };
+    let thetas: Vec<f32> = (1..=12).map(|i| i as f32 / 10.0).collect();
+    let distributions: [(&str, InitFn); 3] = [
+        ("uniform", inits::uniform_init),
+        ("disc", inits::disc_init),
+        ("spherical", inits::spherical_init),
+    ];
+    let mut rows = Vec::new();
+    for (name, init_fn) in distributions {
+        println!("{}:", name);
+        println!("theta    median       p90          p99          max");
+        let sweep = pollster::block_on(accuracy::theta_sweep(
+            sim_params,
+            init_fn,
+            &thetas,
+            ForceReference::Direct,
+        ))
+        .unwrap();
+        for &(theta, e) in &sweep {
+            println!(
+                "{:<8.2} {:<12.3e} {:<12.3e} {:<12.3e} {:<12.3e}",
+                theta, e.median, e.p90, e.p99, e.max
+            );
+            rows.push((name, theta, e));
+        }
+        match accuracy::choose_theta(&sweep, MAX_P99) {
+            Some(theta) => println!("largest theta with p99 < {}: {}\n", MAX_P99, theta),
+            None => println!("no theta reaches p99 < {}\n", MAX_P99),
+        }
+    }
+    // passing a path also writes the sweep as CSV
+    if let Some(path) = std::env::args_os().nth(1) {
+        accuracy::write_sweep(path, &rows).unwrap();
+    }
+}
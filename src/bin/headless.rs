@@ -4,6 +4,7 @@ use wgpu_n_body::{
     inits,
     runners::OfflineHeadless,
     sims::{SimParams, TreeSim, AddParams},
+    trajectory::TrajectoryWriter,
 };
 
 #[global_allocator]
@@ -14,22 +15,40 @@ const STEPS: usize = 10;
 fn main() {
     let sim_params = SimParams {
         particle_num: 4000000,
-        g: 0.000001,
+        g: 0.000000016,
         e: 0.0001,
         dt: 0.016,
     };
     println!("Initializing Simulation");
     let mut runner = pollster::block_on(OfflineHeadless::<TreeSim>::new(
         sim_params,
-        AddParams::TreeSimParams { theta: 0.75 },
+        AddParams::TreeSimParams {
+            theta: 0.75,
+            collisions: false,
+        },
         inits::uniform_init,
     ))
     .unwrap();
+    // passing a path records every step to a trajectory file for `visualize`
+    let mut recorder = std::env::args_os()
+        .nth(1)
+        .map(|path| TrajectoryWriter::create(path, sim_params).unwrap());
+    if let Some(recorder) = recorder.as_mut() {
+        recorder.write_frame(0, 0.0, &runner.read_particles()).unwrap();
+    }
     println!("Running Simulation");
-    for _ in 0..STEPS {
+    for step in 1..=STEPS {
         let now = Instant::now();
         runner.step();
         println!("Step Duration: {} µs", now.elapsed().as_micros());
+        if let Some(recorder) = recorder.as_mut() {
+            recorder
+                .write_frame(step as u64, runner.time(), &runner.read_particles())
+                .unwrap();
+        }
+    }
+    if let Some(recorder) = recorder.as_mut() {
+        recorder.flush().unwrap();
     }
     println!("Finished Running");
 }
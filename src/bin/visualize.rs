@@ -1,13 +1,13 @@
 use wgpu_n_body::{
     inits, runners,
-    sims::{self, TreeSim, AddParams},
+    sims::{self, AddParams, PlaybackSim, Simulator, TreeSim},
 };
 
 use winit::{
     dpi::LogicalSize,
     event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
-    window::WindowBuilder,
+    window::{Window, WindowBuilder},
 };
 
 #[global_allocator]
@@ -20,23 +20,48 @@ fn main() {
         .with_inner_size(LogicalSize::new(400, 400))
         .build(&event_loop)
         .unwrap();
-    let mut should_render = true;
     window.focus_window();
 
     let sim_params = sims::SimParams {
         particle_num: 100000,
-        g: 0.00001,
+        g: 0.000000016,
         e: 0.0001,
         dt: 0.0016,
     };
-    let mut state = pollster::block_on(runners::OnlineRenderer::<TreeSim>::new(
-        &window,
-        sim_params,
-        AddParams::TreeSimParams { theta: 0.75 },
-        inits::disc_init,
-    ))
-    .unwrap();
+    // passing a trajectory file replays it instead of simulating
+    match std::env::args_os().nth(1) {
+        Some(path) => {
+            let state = pollster::block_on(runners::OnlineRenderer::<PlaybackSim>::new(
+                &window,
+                sim_params,
+                AddParams::PlaybackParams { path: path.into() },
+                inits::disc_init,
+            ))
+            .unwrap();
+            run(event_loop, window, state)
+        }
+        None => {
+            let state = pollster::block_on(runners::OnlineRenderer::<TreeSim>::new(
+                &window,
+                sim_params,
+                AddParams::TreeSimParams {
+                    theta: 0.75,
+                    collisions: false,
+                },
+                inits::disc_init,
+            ))
+            .unwrap();
+            run(event_loop, window, state)
+        }
+    }
+}
 
+fn run<T: Simulator + 'static>(
+    event_loop: EventLoop<()>,
+    window: Window,
+    mut state: runners::OnlineRenderer<T>,
+) -> ! {
+    let mut should_render = true;
     event_loop.run(move |event, _, control_flow| match event {
         Event::RedrawRequested(window_id) if window_id == window.id() => {
             state.update();
@@ -51,13 +76,9 @@ fn main() {
             }
             //std::process::exit(0);
         }
-        Event::MainEventsCleared => {
-            // RedrawRequested will only trigger once, unless we manually
-            // request it.
-            if should_render {
-                window.request_redraw();
-            }
-        }
+        // RedrawRequested will only trigger once, unless we manually
+        // request it.
+        Event::MainEventsCleared if should_render => window.request_redraw(),
         Event::WindowEvent {
             ref event,
             window_id,
@@ -85,6 +106,25 @@ fn main() {
                     },
                 ..
             } => *control_flow = ControlFlow::Exit,
+            // halve/double the timestep while running
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode:
+                            Some(keycode @ (VirtualKeyCode::Minus | VirtualKeyCode::Equals)),
+                        ..
+                    },
+                ..
+            } => {
+                let sim_params = state.sim_params();
+                let dt = match keycode {
+                    VirtualKeyCode::Minus => sim_params.dt / 2.0,
+                    _ => sim_params.dt * 2.0,
+                };
+                println!("dt = {}", dt);
+                state.set_sim_params(sims::SimParams { dt, ..sim_params });
+            }
             _ => {
                 state.input(event);
             }
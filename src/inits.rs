@@ -1,4 +1,4 @@
-use crate::sims::{Particle, SimParams};
+use crate::sims::{species, Particle, SimParams};
 
 use glam::Vec3A;
 use rand::{distributions::Uniform, prelude::Distribution};
@@ -7,7 +7,7 @@ pub fn uniform_init(sim_params: &SimParams) -> Vec<Particle> {
     let mut rng = rand::thread_rng();
     let pos_unif = Uniform::new_inclusive(-1.0, 1.0);
     let mut initial_particles = Vec::with_capacity(sim_params.particle_num as usize);
-    for _ in 0..sim_params.particle_num {
+    for i in 0..sim_params.particle_num {
         initial_particles.push(Particle {
             position: [
                 pos_unif.sample(&mut rng),
@@ -21,12 +21,19 @@ pub fn uniform_init(sim_params: &SimParams) -> Vec<Particle> {
             ],
             acceleration: [0.0, 0.0, 0.0],
             mass: 1.0,
+            id: i,
+            level: 0,
+            radius: 0.0,
+            species: species::DARK_MATTER,
+            charge: 0.0,
+            potential: 0.0,
         });
     }
     initial_particles
 }
 
 pub fn disc_init(sim_params: &SimParams) -> Vec<Particle> {
+    const CENTRAL_MASS: f32 = 150000.0;
     let mut rng = rand::thread_rng();
     let unif = Uniform::new_inclusive(-1.0, 1.0);
     let mut initial_particles = Vec::with_capacity(sim_params.particle_num as usize);
@@ -34,20 +41,33 @@ pub fn disc_init(sim_params: &SimParams) -> Vec<Particle> {
         position: [0.0; 3],
         velocity: [0.0; 3],
         acceleration: [0.0; 3],
-        mass: 150000.0,
+        mass: CENTRAL_MASS,
+        id: 0,
+        level: 0,
+        radius: 0.0,
+        species: species::BLACK_HOLE,
+        charge: 0.0,
+        potential: 0.0,
     });
-    for _ in 1..sim_params.particle_num {
+    for i in 1..sim_params.particle_num {
         let mut pos: Vec3A = Vec3A::new(unif.sample(&mut rng), unif.sample(&mut rng), 0.0);
         while pos.length() > 1.0 || pos.length() < 0.25 {
             pos = Vec3A::new(unif.sample(&mut rng), unif.sample(&mut rng), unif.sample(&mut rng) * 0.1);
         }
         pos *= pos.length();
-        let vel = (sim_params.g * 1000.0 / pos.length()).sqrt() * pos.cross(Vec3A::Z).normalize();
+        let vel = (sim_params.g * CENTRAL_MASS / pos.length()).sqrt()
+            * pos.cross(Vec3A::Z).normalize();
         initial_particles.push(Particle {
             position: pos.to_array(),
             velocity: vel.to_array(),
             acceleration: [0.0; 3],
             mass: 1.0,
+            id: i,
+            level: 0,
+            radius: 0.0,
+            species: species::STARS,
+            charge: 0.0,
+            potential: 0.0,
         })
     }
     initial_particles
@@ -58,7 +78,7 @@ pub fn spherical_init(sim_params: &SimParams) -> Vec<Particle> {
     let mut rng = rand::thread_rng();
     let unif = Uniform::new_inclusive(-1.0, 1.0);
     let mut initial_particles = Vec::with_capacity(sim_params.particle_num as usize);
-    for _ in 0..sim_params.particle_num {
+    for i in 0..sim_params.particle_num {
         let mut pos: Vec3A = Vec3A::new(
             unif.sample(&mut rng),
             unif.sample(&mut rng),
@@ -77,7 +97,23 @@ pub fn spherical_init(sim_params: &SimParams) -> Vec<Particle> {
             velocity: vel.to_array(),
             acceleration: [0.0; 3],
             mass: unif.sample(&mut rng) + 2.0,
+            id: i,
+            level: 0,
+            radius: 0.0,
+            species: species::DARK_MATTER,
+            charge: 0.0,
+            potential: 0.0,
         });
     }
     initial_particles
 }
+
+/// Same as `disc_init` but the disc is made of massless tracers orbiting the central body.
+pub fn tracer_disc_init(sim_params: &SimParams) -> Vec<Particle> {
+    let mut initial_particles = disc_init(sim_params);
+    for particle in initial_particles.iter_mut().skip(1) {
+        particle.mass = 0.0;
+        particle.species = species::TRACER;
+    }
+    initial_particles
+}
@@ -1,5 +1,7 @@
+pub mod analysis;
 pub mod inits;
 pub mod runners;
 pub mod sims;
+pub mod trajectory;
 
 mod utils;
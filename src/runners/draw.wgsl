@@ -2,21 +2,35 @@ struct CameraUniform {
     view_proj: mat4x4<f32>;
 };
 
+struct SpeciesColours {
+    colours: array<vec4<f32>, 8>;
+};
+
 [[group(0), binding(0)]] var<uniform> camera: CameraUniform;
+[[group(0), binding(1)]] var<uniform> species: SpeciesColours;
+
+struct VertexOutput {
+    [[builtin(position)]] position: vec4<f32>;
+    [[location(0)]] colour: vec4<f32>;
+};
 
 [[stage(vertex)]]
 fn main_vs(
     [[location(0)]] particle_pos: vec3<f32>,
     [[location(1)]] particle_vel: vec3<f32>,
+    [[location(4)]] particle_species: u32,
     [[location(3)]] position: vec2<f32>,
-) -> [[builtin(position)]] vec4<f32> {
+) -> VertexOutput {
     let v_pos = vec4<f32>(
         position.x, position.y, 0.0, 0.0
     );
-    return camera.view_proj * vec4<f32>(particle_pos, 1.0) + v_pos;
+    var out: VertexOutput;
+    out.position = camera.view_proj * vec4<f32>(particle_pos, 1.0) + v_pos;
+    out.colour = species.colours[min(particle_species, 7u)];
+    return out;
 }
 
 [[stage(fragment)]]
-fn main_fs() -> [[location(0)]] vec4<f32> {
-    return vec4<f32>(1.0, 1.0, 1.0, 0.25);
+fn main_fs(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return in.colour;
 }
@@ -9,35 +9,30 @@ use anyhow::Context;
 async fn get_device_and_queue(
     adapter: &wgpu::Adapter,
 ) -> anyhow::Result<(wgpu::Device, wgpu::Queue, bool)> {
-    let (device, queue) = adapter
-        .request_device(
+    // bindings and dispatches as large as the adapter allows, simulators check their buffers
+    // against the device limits
+    let adapter_limits = adapter.limits();
+    let limits = wgpu::Limits {
+        max_storage_buffer_binding_size: adapter_limits.max_storage_buffer_binding_size,
+        max_compute_workgroups_per_dimension: adapter_limits.max_compute_workgroups_per_dimension,
+        ..wgpu::Limits::default()
+    };
+    let request = |features| {
+        adapter.request_device(
             &wgpu::DeviceDescriptor {
                 label: None,
-                features: wgpu::Features::MAPPABLE_PRIMARY_BUFFERS,
-                limits: wgpu::Limits {
-                    max_storage_buffer_binding_size: 1073741824,
-                    ..wgpu::Limits::default()
-                },
+                features,
+                limits: limits.clone(),
             },
             None,
         )
-        .await
-        .unwrap_or(
-            adapter
-                .request_device(
-                    &wgpu::DeviceDescriptor {
-                        label: None,
-                        features: wgpu::Features::empty(),
-                        limits: wgpu::Limits {
-                            max_storage_buffer_binding_size: 1073741824,
-                            ..wgpu::Limits::default()
-                        },
-                    },
-                    None,
-                )
-                .await
-                .context("Failed to create logical device and queue")?,
-        );
+    };
+    let (device, queue) = match request(wgpu::Features::MAPPABLE_PRIMARY_BUFFERS).await {
+        Ok(device_and_queue) => device_and_queue,
+        Err(_) => request(wgpu::Features::empty())
+            .await
+            .context("Failed to create logical device and queue")?,
+    };
     let mappable_primary_buffers = device
         .features()
         .contains(wgpu::Features::MAPPABLE_PRIMARY_BUFFERS);
@@ -8,6 +8,8 @@ where
     sim: T,
     device: wgpu::Device,
     queue: wgpu::Queue,
+    time: f64,
+    adaptive_timestep: Option<sims::AdaptiveTimestep>,
 }
 
 impl<T> OfflineHeadless<T>
@@ -31,15 +33,117 @@ where
         let (device, queue, mappable_primary_buffers) = super::get_device_and_queue(&adapter).await?;
         let sim = Simulator::new(&device, sim_params, add_params, mappable_primary_buffers, init_fn)?;
 
-        Ok(Self { sim, device, queue })
+        Ok(Self {
+            sim,
+            device,
+            queue,
+            time: 0.0,
+            adaptive_timestep: None,
+        })
     }
 
 
     pub fn step(&mut self) {
+        let step_dt = self.sim.step_dt();
         let encoder = self.sim.encode(&self.device, &self.queue);
         self.queue.submit(Some(encoder.finish()));
 
         self.sim.cleanup();
         self.device.poll(wgpu::Maintain::Wait);
+        self.time += step_dt as f64;
+
+        if let Some(adaptive_timestep) = self.adaptive_timestep {
+            // read after the step, collisions may have merged particles
+            let sim_params = self.sim.sim_params();
+            let criterion_dt = self
+                .sim
+                .min_timestep(&self.device, &self.queue, &adaptive_timestep);
+            let dt = adaptive_timestep.next_dt(sim_params.dt, criterion_dt);
+            self.sim
+                .set_sim_params(&self.queue, sims::SimParams { dt, ..sim_params });
+        }
+    }
+
+    /// Simulation time elapsed over all steps so far.
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    /// Chooses `dt` after every step from `adaptive_timestep`, or keeps the current `dt` fixed
+    /// when `None`.
+    pub fn set_adaptive_timestep(&mut self, adaptive_timestep: Option<sims::AdaptiveTimestep>) {
+        self.adaptive_timestep = adaptive_timestep;
+    }
+
+    /// Enables power-of-two block timesteps, or returns to one global `dt` when `None`. Every
+    /// step then advances a single substep.
+    pub fn set_block_timesteps(&mut self, block_timesteps: Option<sims::BlockTimesteps>) {
+        self.sim.set_block_timesteps(&self.device, block_timesteps);
+    }
+
+    pub fn read_particles(&self) -> Vec<sims::Particle> {
+        self.sim.read_particles(&self.device, &self.queue)
+    }
+
+    pub fn write_particles(&mut self, particles: &[sims::Particle]) -> anyhow::Result<()> {
+        self.sim.write_particles(&self.queue, particles)
+    }
+
+    pub fn sim_params(&self) -> sims::SimParams {
+        self.sim.sim_params()
+    }
+
+    pub fn set_sim_params(&mut self, sim_params: sims::SimParams) {
+        self.sim.set_sim_params(&self.queue, sim_params);
+    }
+
+    pub fn set_add_params(&mut self, add_params: sims::AddParams) -> anyhow::Result<()> {
+        self.sim.set_add_params(&self.queue, add_params)
+    }
+
+    /// Appends `particles` before the next step, growing the simulator's buffers if needed.
+    pub fn add_particles(&mut self, particles: &[sims::Particle]) -> anyhow::Result<()> {
+        self.sim.add_particles(&self.device, &self.queue, particles)
+    }
+
+    /// Removes the particles with the given IDs before the next step.
+    pub fn remove_particles(&mut self, ids: &[u32]) -> anyhow::Result<()> {
+        self.sim.remove_particles(&self.device, &self.queue, ids)
+    }
+
+    pub fn particle_count(&self) -> u32 {
+        self.sim.particle_count()
+    }
+
+    pub fn set_species(&mut self, species: &[sims::Species]) -> anyhow::Result<()> {
+        self.sim.set_species(&self.queue, species)
+    }
+
+    /// Recompiles the force kernels with `force_law` for all following steps.
+    pub fn set_force_law(&mut self, force_law: sims::ForceLaw) -> anyhow::Result<()> {
+        self.sim.set_force_law(&self.device, force_law)
+    }
+
+    /// Enables or disables first post-Newtonian corrections, see `Simulator::set_post_newtonian`.
+    pub fn set_post_newtonian(
+        &mut self,
+        post_newtonian: Option<sims::PostNewtonian>,
+    ) -> anyhow::Result<()> {
+        self.sim.set_post_newtonian(&self.device, post_newtonian)
+    }
+
+    /// Switches to or from the Wisdom-Holman integrator, see `Simulator::set_wisdom_holman`.
+    pub fn set_wisdom_holman(
+        &mut self,
+        wisdom_holman: Option<sims::WisdomHolman>,
+    ) -> anyhow::Result<()> {
+        self.sim.set_wisdom_holman(&self.device, wisdom_holman)
+    }
+
+    pub fn set_external_potentials(
+        &mut self,
+        potentials: &[sims::ExternalPotential],
+    ) -> anyhow::Result<()> {
+        self.sim.set_external_potentials(&self.queue, potentials)
     }
 }
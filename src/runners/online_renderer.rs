@@ -1,6 +1,6 @@
 use std::borrow::Cow;
 
-use crate::{sims, sims::Simulator};
+use crate::{sims, sims::species::species_colours, sims::Simulator};
 use anyhow::Context;
 use wgpu::util::DeviceExt;
 use winit::{
@@ -50,7 +50,7 @@ impl Camera {
     fn build_view_projection_matrix(&self) -> cgmath::Matrix4<f32> {
         let view = cgmath::Matrix4::look_at_rh(self.eye, self.target, self.up);
         let proj = cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar);
-        return OPENGL_TO_WGPU_MATRIX * proj * view;
+        OPENGL_TO_WGPU_MATRIX * proj * view
     }
 }
 
@@ -179,9 +179,12 @@ where
     camera: Camera,
     camera_uniform: CameraUniform,
     camera_buffer: wgpu::Buffer,
+    species_buffer: wgpu::Buffer,
     camera_controller: CameraController,
     camera_bind_group: wgpu::BindGroup,
     frame_num: usize,
+    time: f64,
+    adaptive_timestep: Option<sims::AdaptiveTimestep>,
 }
 
 impl<T> OnlineRenderer<T>
@@ -247,27 +250,51 @@ where
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
+        let species_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Species Colour Buffer"),
+            contents: bytemuck::cast_slice(&species_colours(&sims::Species::default_table())),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
         let camera_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::VERTEX,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
                     },
-                    count: None,
-                }],
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::VERTEX,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
                 label: Some("camera_bind_group_layout"),
             });
 
         let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: &camera_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: camera_buffer.as_entire_binding(),
-            }],
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: camera_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: species_buffer.as_entire_binding(),
+                },
+            ],
             label: Some("camera_bind_group"),
         });
 
@@ -327,9 +354,12 @@ where
             camera,
             camera_uniform,
             camera_buffer,
+            species_buffer,
             camera_bind_group,
             camera_controller,
             frame_num: 0,
+            time: 0.0,
+            adaptive_timestep: None,
         })
     }
 
@@ -356,6 +386,7 @@ where
             color_attachments: &color_attachements,
             depth_stencil_attachment: None,
         };
+        let step_dt = self.sim.step_dt();
         let mut encoder = self.sim.encode(&self.device, &self.queue);
         encoder.push_debug_group("draw bodies");
         {
@@ -364,7 +395,7 @@ where
             rpass.set_bind_group(0, &self.camera_bind_group, &[]);
             rpass.set_vertex_buffer(0, self.sim.dest_particle_slice());
             rpass.set_vertex_buffer(1, self.vertices_buffer.slice(..));
-            rpass.draw(0..3, 0..self.sim.sim_params().particle_num as u32);
+            rpass.draw(0..3, 0..self.sim.particle_count());
         }
         encoder.pop_debug_group();
 
@@ -373,10 +404,39 @@ where
         self.queue.submit(Some(encoder.finish()));
         output.present();
         self.sim.cleanup();
+        self.time += step_dt as f64;
+
+        if let Some(adaptive_timestep) = self.adaptive_timestep {
+            // read after the step, collisions may have merged particles
+            let sim_params = self.sim.sim_params();
+            let criterion_dt = self
+                .sim
+                .min_timestep(&self.device, &self.queue, &adaptive_timestep);
+            let dt = adaptive_timestep.next_dt(sim_params.dt, criterion_dt);
+            self.sim
+                .set_sim_params(&self.queue, sims::SimParams { dt, ..sim_params });
+        }
 
         Ok(())
     }
 
+    /// Simulation time elapsed over all rendered frames so far.
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    /// Chooses `dt` after every frame from `adaptive_timestep`, or keeps the current `dt` fixed
+    /// when `None`.
+    pub fn set_adaptive_timestep(&mut self, adaptive_timestep: Option<sims::AdaptiveTimestep>) {
+        self.adaptive_timestep = adaptive_timestep;
+    }
+
+    /// Enables power-of-two block timesteps, or returns to one global `dt` when `None`. Every
+    /// frame then advances a single substep.
+    pub fn set_block_timesteps(&mut self, block_timesteps: Option<sims::BlockTimesteps>) {
+        self.sim.set_block_timesteps(&self.device, block_timesteps);
+    }
+
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
             self.size = new_size;
@@ -388,7 +448,74 @@ where
 
     #[allow(unused_variables)]
     pub fn input(&mut self, event: &WindowEvent) -> bool {
-        self.camera_controller.process_events(event)
+        self.sim.input(event) || self.camera_controller.process_events(event)
+    }
+
+    pub fn sim_params(&self) -> sims::SimParams {
+        self.sim.sim_params()
+    }
+
+    /// Takes effect from the next rendered frame.
+    pub fn set_sim_params(&mut self, sim_params: sims::SimParams) {
+        self.sim.set_sim_params(&self.queue, sim_params);
+    }
+
+    pub fn set_add_params(&mut self, add_params: sims::AddParams) -> anyhow::Result<()> {
+        self.sim.set_add_params(&self.queue, add_params)
+    }
+
+    /// Appends `particles` before the next step, growing the simulator's buffers if needed.
+    pub fn add_particles(&mut self, particles: &[sims::Particle]) -> anyhow::Result<()> {
+        self.sim.add_particles(&self.device, &self.queue, particles)
+    }
+
+    /// Removes the particles with the given IDs before the next step.
+    pub fn remove_particles(&mut self, ids: &[u32]) -> anyhow::Result<()> {
+        self.sim.remove_particles(&self.device, &self.queue, ids)
+    }
+
+    pub fn particle_count(&self) -> u32 {
+        self.sim.particle_count()
+    }
+
+    /// Replaces the species table, both the per-species softening of the simulator and the
+    /// colours particles are drawn with.
+    pub fn set_species(&mut self, species: &[sims::Species]) -> anyhow::Result<()> {
+        self.sim.set_species(&self.queue, species)?;
+        self.queue.write_buffer(
+            &self.species_buffer,
+            0,
+            bytemuck::cast_slice(&species_colours(species)),
+        );
+        Ok(())
+    }
+
+    /// Recompiles the force kernels with `force_law` for all following steps.
+    pub fn set_force_law(&mut self, force_law: sims::ForceLaw) -> anyhow::Result<()> {
+        self.sim.set_force_law(&self.device, force_law)
+    }
+
+    /// Enables or disables first post-Newtonian corrections, see `Simulator::set_post_newtonian`.
+    pub fn set_post_newtonian(
+        &mut self,
+        post_newtonian: Option<sims::PostNewtonian>,
+    ) -> anyhow::Result<()> {
+        self.sim.set_post_newtonian(&self.device, post_newtonian)
+    }
+
+    /// Switches to or from the Wisdom-Holman integrator, see `Simulator::set_wisdom_holman`.
+    pub fn set_wisdom_holman(
+        &mut self,
+        wisdom_holman: Option<sims::WisdomHolman>,
+    ) -> anyhow::Result<()> {
+        self.sim.set_wisdom_holman(&self.device, wisdom_holman)
+    }
+
+    pub fn set_external_potentials(
+        &mut self,
+        potentials: &[sims::ExternalPotential],
+    ) -> anyhow::Result<()> {
+        self.sim.set_external_potentials(&self.queue, potentials)
     }
 
     pub fn update(&mut self) {
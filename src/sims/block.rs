@@ -0,0 +1,302 @@
+use super::{Particle, SimParams};
+
+/// Power-of-two block timesteps. Each particle is kicked every `dt / 2^level`, where `dt` is
+/// `SimParams::dt` and `level` is the smallest level (up to `max_level`) whose step is below
+/// `eta * sqrt(eps / |a|)` for the particle's own acceleration. `eps` is the softening length
+/// `e^(1/3)`. Kicks are merged leapfrog half-kicks, so while enabled the stored velocities
+/// lead the positions by half of each particle's own step.
+#[derive(Copy, Clone, Debug)]
+pub struct BlockTimesteps {
+    pub max_level: u32,
+    pub eta: f32,
+}
+
+impl Default for BlockTimesteps {
+    fn default() -> Self {
+        BlockTimesteps {
+            max_level: 6,
+            eta: 0.05,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct BlockParams {
+    substep: u32,
+    max_level: u32,
+    eta: f32,
+    softening: f32,
+    first: u32,
+    _padding: [u32; 3],
+}
+
+/// Runs the block timestep entry points of a force kernel. Every substep only the particles on
+/// a level boundary are collected into an active list and kicked (via indirect dispatch), then
+/// all particles drift by the finest step.
+pub(crate) struct BlockStepper {
+    config: BlockTimesteps,
+    substep: u32,
+    first: bool,
+    params_buffer: wgpu::Buffer,
+    active_buffer: wgpu::Buffer,
+    dispatch_buffer: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    select_pipeline: wgpu::ComputePipeline,
+    args_pipeline: wgpu::ComputePipeline,
+    kick_pipeline: wgpu::ComputePipeline,
+    drift_pipeline: wgpu::ComputePipeline,
+}
+
+impl BlockStepper {
+    /// `compute_module` must contain `block.wgsl`, and `kernel_bind_group_layouts` are the force
+    /// kernel's own layouts (groups 0 and 1), the block bindings follow them.
+    pub fn new(
+        device: &wgpu::Device,
+        config: BlockTimesteps,
+        compute_module: &wgpu::ShaderModule,
+        kernel_bind_group_layouts: &[&wgpu::BindGroupLayout],
+        particle_num: u32,
+    ) -> Self {
+        let params_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Block Params Buffer"),
+            size: std::mem::size_of::<BlockParams>() as _,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let active_buffer = Self::create_active_buffer(device, particle_num);
+        let dispatch_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Active Dispatch Buffer"),
+            size: (std::mem::size_of::<u32>() * 3) as _,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT,
+            mapped_at_creation: false,
+        });
+
+        let block_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Block Bind Group Layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: wgpu::BufferSize::new(
+                                std::mem::size_of::<BlockParams>() as _,
+                            ),
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: wgpu::BufferSize::new(
+                                (std::mem::size_of::<u32>() * 3) as _,
+                            ),
+                        },
+                        count: None,
+                    },
+                ],
+            });
+
+        let bind_group = Self::create_bind_group(
+            device,
+            &block_bind_group_layout,
+            &params_buffer,
+            &active_buffer,
+            &dispatch_buffer,
+        );
+
+        let [select_pipeline, args_pipeline, kick_pipeline, drift_pipeline] =
+            Self::create_pipelines(
+                device,
+                compute_module,
+                kernel_bind_group_layouts,
+                &block_bind_group_layout,
+            );
+
+        Self {
+            config,
+            substep: 0,
+            first: true,
+            params_buffer,
+            active_buffer,
+            dispatch_buffer,
+            bind_group_layout: block_bind_group_layout,
+            bind_group,
+            select_pipeline,
+            args_pipeline,
+            kick_pipeline,
+            drift_pipeline,
+        }
+    }
+
+    /// Rebuilds the pipelines from a recompiled force kernel (e.g. after the force law changed),
+    /// keeping the current levels and substep.
+    pub fn set_module(
+        &mut self,
+        device: &wgpu::Device,
+        compute_module: &wgpu::ShaderModule,
+        kernel_bind_group_layouts: &[&wgpu::BindGroupLayout],
+    ) {
+        [
+            self.select_pipeline,
+            self.args_pipeline,
+            self.kick_pipeline,
+            self.drift_pipeline,
+        ] = Self::create_pipelines(
+            device,
+            compute_module,
+            kernel_bind_group_layouts,
+            &self.bind_group_layout,
+        );
+    }
+
+    /// Select, args, kick and drift pipelines.
+    fn create_pipelines(
+        device: &wgpu::Device,
+        compute_module: &wgpu::ShaderModule,
+        kernel_bind_group_layouts: &[&wgpu::BindGroupLayout],
+        block_bind_group_layout: &wgpu::BindGroupLayout,
+    ) -> [wgpu::ComputePipeline; 4] {
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Block Pipeline Layout"),
+            bind_group_layouts: &[kernel_bind_group_layouts, &[block_bind_group_layout]].concat(),
+            push_constant_ranges: &[],
+        });
+        ["block_select", "block_args", "block_kick", "block_drift"].map(|entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: compute_module,
+                entry_point,
+            })
+        })
+    }
+
+    /// Makes room for `particle_num` active particles, keeping the current levels and substep.
+    pub fn resize(&mut self, device: &wgpu::Device, particle_num: u32) {
+        self.active_buffer = Self::create_active_buffer(device, particle_num);
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.params_buffer,
+            &self.active_buffer,
+            &self.dispatch_buffer,
+        );
+    }
+
+    /// Active particle count followed by their indices.
+    fn create_active_buffer(device: &wgpu::Device, particle_num: u32) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Active Particle Buffer"),
+            size: (std::mem::size_of::<u32>() * (particle_num as usize + 1)) as _,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        params_buffer: &wgpu::Buffer,
+        active_buffer: &wgpu::Buffer,
+        dispatch_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Block Bind Group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: active_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: dispatch_buffer.as_entire_binding(),
+                },
+            ],
+        })
+    }
+
+    /// Length of one substep, the finest level's step.
+    pub fn substep_dt(&self, sim_params: &SimParams) -> f32 {
+        sim_params.dt / (1u32 << self.config.max_level) as f32
+    }
+
+    /// Records one substep from `src` into `dst`. `kernel_bind_groups` are the force kernel's
+    /// bind groups, the first one reading `src` and writing `dst`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn encode(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        sim_params: &SimParams,
+        kernel_bind_groups: &[&wgpu::BindGroup],
+        src: &wgpu::Buffer,
+        dst: &wgpu::Buffer,
+        work_group_count: u32,
+    ) {
+        let params = BlockParams {
+            substep: self.substep,
+            max_level: self.config.max_level,
+            eta: self.config.eta,
+            softening: sim_params.e.cbrt(),
+            first: self.first as u32,
+            _padding: [0; 3],
+        };
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[params]));
+        // reset active particle count
+        queue.write_buffer(&self.active_buffer, 0, bytemuck::cast_slice(&[0u32]));
+
+        // inactive particles carry over unchanged
+        encoder.copy_buffer_to_buffer(
+            src,
+            0,
+            dst,
+            0,
+            (std::mem::size_of::<Particle>() * sim_params.particle_num as usize) as _,
+        );
+
+        encoder.push_debug_group("block timestep substep");
+        {
+            let mut cpass =
+                encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+            for (i, bind_group) in kernel_bind_groups.iter().enumerate() {
+                cpass.set_bind_group(i as u32, bind_group, &[]);
+            }
+            cpass.set_bind_group(kernel_bind_groups.len() as u32, &self.bind_group, &[]);
+            cpass.set_pipeline(&self.select_pipeline);
+            cpass.dispatch(work_group_count, 1, 1);
+            cpass.set_pipeline(&self.args_pipeline);
+            cpass.dispatch(1, 1, 1);
+            cpass.set_pipeline(&self.kick_pipeline);
+            cpass.dispatch_indirect(&self.dispatch_buffer, 0);
+            cpass.set_pipeline(&self.drift_pipeline);
+            cpass.dispatch(work_group_count, 1, 1);
+        }
+        encoder.pop_debug_group();
+
+        self.first = false;
+        self.substep = (self.substep + 1) % (1 << self.config.max_level);
+    }
+}
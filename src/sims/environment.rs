@@ -0,0 +1,86 @@
+use anyhow::Result;
+use wgpu::util::DeviceExt;
+
+use super::potential::PotentialsUniform;
+use super::species::SpeciesUniform;
+use super::{ExternalPotential, Species};
+
+/// Uniforms shared by the force kernels beside the particles themselves (group 1): the
+/// external potentials and the per-species table.
+pub(crate) struct Environment {
+    potentials_buffer: wgpu::Buffer,
+    species_buffer: wgpu::Buffer,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl Environment {
+    /// Starts out without any potentials and with `SimParams::e` for every species.
+    pub fn new(device: &wgpu::Device) -> Self {
+        let potentials_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("External Potentials Buffer"),
+            contents: bytemuck::cast_slice(&[PotentialsUniform::default()]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let species_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Species Buffer"),
+            contents: bytemuck::cast_slice(&[SpeciesUniform::default()]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let uniform_entry = |binding, size| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: wgpu::BufferSize::new(size as _),
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Environment Bind Group Layout"),
+            entries: &[
+                uniform_entry(0, std::mem::size_of::<PotentialsUniform>()),
+                uniform_entry(1, std::mem::size_of::<SpeciesUniform>()),
+            ],
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Environment Bind Group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: potentials_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: species_buffer.as_entire_binding(),
+                },
+            ],
+        });
+        Self {
+            potentials_buffer,
+            species_buffer,
+            bind_group_layout,
+            bind_group,
+        }
+    }
+
+    /// Replaces the active potentials for all following steps.
+    pub fn write_potentials(
+        &self,
+        queue: &wgpu::Queue,
+        potentials: &[ExternalPotential],
+    ) -> Result<()> {
+        let uniform = PotentialsUniform::new(potentials)?;
+        queue.write_buffer(&self.potentials_buffer, 0, bytemuck::cast_slice(&[uniform]));
+        Ok(())
+    }
+
+    /// Replaces the species table for all following steps.
+    pub fn write_species(&self, queue: &wgpu::Queue, species: &[Species]) -> Result<()> {
+        let uniform = SpeciesUniform::new(species)?;
+        queue.write_buffer(&self.species_buffer, 0, bytemuck::cast_slice(&[uniform]));
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+/// Pairwise interaction evaluated by the naive and tree kernels. Every law is scaled by
+/// `SimParams::g` and softened by adding `e` (or the species softening) to `r^3`. Only massive
+/// particles (and tree nodes) act as sources.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum ForceLaw {
+    /// Inverse-square attraction between masses
+    #[default]
+    Newtonian,
+    /// Newtonian attraction screened by `exp(-r / length)`
+    Yukawa { length: f32 },
+    /// Inverse-square interaction between `Particle::charge`s, like charges repel. The
+    /// acceleration of a particle is its charge-to-mass ratio times the field, tree nodes
+    /// approximate their charge at the centre of mass.
+    Coulomb,
+    /// Attraction between masses falling off as `r^-exponent`
+    PowerLaw { exponent: f32 },
+}
+
+impl ForceLaw {
+    /// Fails for parameters the kernels can't evaluate: non-finite values, a non-positive Yukawa
+    /// `length` and power law exponents of -1 or below.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match *self {
+            ForceLaw::Newtonian | ForceLaw::Coulomb => {}
+            ForceLaw::Yukawa { length } => anyhow::ensure!(
+                length.is_finite() && length > 0.0,
+                "Yukawa length has to be positive and finite, got {}",
+                length
+            ),
+            ForceLaw::PowerLaw { exponent } => anyhow::ensure!(
+                exponent.is_finite() && exponent > -1.0,
+                "Power law exponent has to be finite and greater than -1, got {}",
+                exponent
+            ),
+        }
+        Ok(())
+    }
+
+    /// WGSL snippet defining `pairAcc`, the acceleration a sink feels from a source at offset
+    /// `d` (from sink to source) and distance `r`, and `pairPot`, the matching potential.
+    /// `sink_qm` is the sink's charge-to-mass ratio. Potentials are softened by replacing `r^n`
+    /// with `r^n + e` like the accelerations, so they are only exact without softening.
+    pub fn wgsl(&self) -> String {
+        let (acc, pot) = match self {
+            ForceLaw::Newtonian => (
+                "return g * source_mass / (r * r * r + e) * d;".to_string(),
+                "return -g * source_mass / pow(r * r * r + e, 1.0 / 3.0);".to_string(),
+            ),
+            ForceLaw::Yukawa { length } => (
+                format!(
+                    "let x = r / {:?};\n    \
+                     return g * source_mass * (1.0 + x) * exp(-x) / (r * r * r + e) * d;",
+                    length
+                ),
+                format!(
+                    "return -g * source_mass * exp(-r / {:?}) / pow(r * r * r + e, 1.0 / 3.0);",
+                    length
+                ),
+            ),
+            ForceLaw::Coulomb => (
+                "return -g * source_charge * sink_qm / (r * r * r + e) * d;".to_string(),
+                "return g * source_charge * sink_qm / pow(r * r * r + e, 1.0 / 3.0);".to_string(),
+            ),
+            ForceLaw::PowerLaw { exponent } => (
+                format!(
+                    "return g * source_mass / (pow(r, {:?}) + e) * d;",
+                    exponent + 1.0
+                ),
+                match *exponent == 1.0 {
+                    // logarithmic potential, r^2 + e stands in for r^2
+                    true => "return g * source_mass * 0.5 * log(r * r + e);".to_string(),
+                    false => format!(
+                        "return -g * source_mass / ({:?} * pow(pow(r, {:?}) + e, {:?}));",
+                        exponent - 1.0,
+                        exponent + 1.0,
+                        (exponent - 1.0) / (exponent + 1.0)
+                    ),
+                },
+            ),
+        };
+        format!(
+            "fn pairAcc(d: vec3<f32>, r: f32, g: f32, e: f32, source_mass: f32, \
+             source_charge: f32, sink_qm: f32) -> vec3<f32> {{\n    {}\n}}\n\
+             fn pairPot(r: f32, g: f32, e: f32, source_mass: f32, source_charge: f32, \
+             sink_qm: f32) -> f32 {{\n    {}\n}}\n",
+            acc, pot
+        )
+    }
+
+    /// Complete source of a force kernel (`naive.wgsl` or `tree.wgsl`) with its shared
+    /// preludes, this law and additional `entry_points` (e.g. `block.wgsl`).
+    pub(crate) fn kernel_source(&self, kernel: &str, entry_points: &str) -> String {
+        [
+            include_str!("shaders/particle.wgsl"),
+            include_str!("shaders/potential.wgsl"),
+            include_str!("shaders/species.wgsl"),
+            &self.wgsl(),
+            kernel,
+            entry_points,
+        ]
+        .concat()
+    }
+}
@@ -1,8 +1,30 @@
+use rayon::slice::ParallelSliceMut;
+
+mod block;
+mod environment;
+mod force_law;
 mod naive;
+mod octree;
+mod playback;
+mod post_newtonian;
+mod potential;
+pub mod species;
+mod timestep;
 mod tree;
+mod wisdom_holman;
 
+pub use block::BlockTimesteps;
+pub use force_law::ForceLaw;
 pub use naive::NaiveSim;
+pub use octree::{Octree, OctreeNode, OctreeStats, TRAVERSAL_STACK_SIZE};
+pub(crate) use octree::BoundedNode;
+pub use playback::PlaybackSim;
+pub use post_newtonian::PostNewtonian;
+pub use potential::{ExternalPotential, MAX_EXTERNAL_POTENTIALS};
+pub use species::{Species, MAX_SPECIES};
+pub use timestep::AdaptiveTimestep;
 pub use tree::TreeSim;
+pub use wisdom_holman::WisdomHolman;
 
 pub const PARTICLES_PER_GROUP: u32 = 64;
 
@@ -12,17 +34,44 @@ pub struct Particle {
     pub position: [f32; 3],
     pub velocity: [f32; 3],
     pub acceleration: [f32; 3],
+    /// Zero for massless tracers, which feel gravity but don't source it
     pub mass: f32,
+    /// Stable identifier that follows the body through reordering (e.g. `TreeSim`'s locality
+    /// sort), normally its index in the initial particle set
+    pub id: u32,
+    /// Block timestep level, the particle is kicked every `dt / 2^level` while block timesteps
+    /// are enabled
+    pub level: u32,
+    /// Collision radius, particles closer than the sum of their radii are merged when collisions
+    /// are enabled
+    pub radius: f32,
+    /// Index into the species table (e.g. `species::STARS`)
+    pub species: u32,
+    /// Signed charge, only used by `ForceLaw::Coulomb`
+    pub charge: f32,
+    /// Potential from the massive particles under the current force law at the last force
+    /// evaluation, external potentials excluded. Not updated by the Wisdom-Holman integrator.
+    pub potential: f32,
 }
 
 pub enum AddParams {
     TreeSimParams {
-        theta: f32
+        theta: f32,
+        /// merge particles closer than the sum of their radii after every step
+        collisions: bool,
+    },
+    NaiveSimParams,
+    PlaybackParams {
+        path: std::path::PathBuf
     },
-    NaiveSimParams
 }
 
 impl Particle {
+    /// Whether this is a massless test particle that doesn't contribute to the force field.
+    pub fn is_tracer(&self) -> bool {
+        self.mass == 0.0
+    }
+
     pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         wgpu::VertexBufferLayout {
             array_stride: std::mem::size_of::<Particle>() as wgpu::BufferAddress,
@@ -43,6 +92,11 @@ impl Particle {
                     shader_location: 2,
                     format: wgpu::VertexFormat::Float32x3,
                 },
+                wgpu::VertexAttribute {
+                    offset: 52,
+                    shader_location: 4,
+                    format: wgpu::VertexFormat::Uint32,
+                },
             ],
         }
     }
@@ -63,13 +117,190 @@ impl Default for SimParams {
     fn default() -> Self {
         SimParams {
             particle_num: 10000,
-            g: 0.000001,
+            g: 0.000000016,
             e: 0.0001,
             dt: 0.016,
         }
     }
 }
 
+/// Restores ID order on particles read back from a simulator that reorders them.
+pub fn sort_by_id(particles: &mut [Particle]) {
+    particles.par_sort_unstable_by_key(|p| p.id);
+}
+
+/// Moves tracers behind all massive particles, keeping the relative order of both, and returns
+/// the number of massive particles.
+fn partition_tracers(particles: &mut [Particle]) -> u32 {
+    particles.par_sort_by_key(|p| p.is_tracer());
+    particles.iter().take_while(|p| !p.is_tracer()).count() as u32
+}
+
+/// Euclidean distance between two positions.
+pub(crate) fn distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+/// Capacity to reallocate to when `particle_num` particles don't fit into `capacity`, doubling
+/// so that repeated insertions only reallocate occasionally.
+fn grown_capacity(capacity: u32, particle_num: u32) -> u32 {
+    particle_num.max(capacity.saturating_mul(2)).max(1)
+}
+
+/// Size in bytes of `count` particles.
+fn particle_bytes(count: u32) -> wgpu::BufferAddress {
+    std::mem::size_of::<Particle>() as wgpu::BufferAddress * count as wgpu::BufferAddress
+}
+
+/// Most particles whose buffers can be bound whole and updated by a single dispatch on `device`.
+fn max_particles(device: &wgpu::Device) -> u32 {
+    let limits = device.limits();
+    let binding = limits.max_storage_buffer_binding_size / std::mem::size_of::<Particle>() as u32;
+    let dispatch = limits
+        .max_compute_workgroups_per_dimension
+        .saturating_mul(PARTICLES_PER_GROUP);
+    binding.min(dispatch)
+}
+
+/// Fails with a readable error if buffers of `capacity` particles can't be bound whole or
+/// updated with a single dispatch on `device`. Particles are kept in one buffer per step, so
+/// there is no way around these limits yet.
+fn check_particle_limits(device: &wgpu::Device, sim: &str, capacity: u32) -> anyhow::Result<()> {
+    let limits = device.limits();
+    let size = particle_bytes(capacity);
+    anyhow::ensure!(
+        size <= limits.max_storage_buffer_binding_size as wgpu::BufferAddress,
+        "{} binds all {} particles at once, which needs {} bytes but the device allows storage \
+         bindings of at most {} bytes",
+        sim,
+        capacity,
+        size,
+        limits.max_storage_buffer_binding_size
+    );
+    let work_groups = capacity.div_ceil(PARTICLES_PER_GROUP);
+    anyhow::ensure!(
+        work_groups <= limits.max_compute_workgroups_per_dimension,
+        "{} updates all {} particles in one dispatch of {} work groups but the device allows at \
+         most {}",
+        sim,
+        capacity,
+        work_groups,
+        limits.max_compute_workgroups_per_dimension
+    );
+    Ok(())
+}
+
+/// Creates the pair of primary particle buffers with room for `capacity` particles, both
+/// starting out with `particles`.
+fn create_particle_buffers(
+    device: &wgpu::Device,
+    particles: &[Particle],
+    capacity: u32,
+    mappable_primary_buffers: bool,
+) -> Vec<wgpu::Buffer> {
+    let contents: &[u8] = bytemuck::cast_slice(particles);
+    (0..2)
+        .map(|i| {
+            let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(&format!("Particle Buffer {}", i)),
+                size: particle_bytes(capacity.max(1)),
+                usage: wgpu::BufferUsages::VERTEX
+                    | wgpu::BufferUsages::STORAGE
+                    | wgpu::BufferUsages::COPY_SRC
+                    | wgpu::BufferUsages::COPY_DST
+                    | match mappable_primary_buffers {
+                        true => wgpu::BufferUsages::MAP_READ,
+                        false => wgpu::BufferUsages::empty(),
+                    },
+                mapped_at_creation: true,
+            });
+            buffer.slice(..).get_mapped_range_mut()[..contents.len()].copy_from_slice(contents);
+            buffer.unmap();
+            buffer
+        })
+        .collect()
+}
+
+/// Compiles the force kernel source `kernel` (`naive.wgsl` or `tree.wgsl`) with `force_law` and
+/// the block timestep entry points and creates its main pipeline. The module is kept for the
+/// block timestep pipelines.
+fn create_force_kernel(
+    device: &wgpu::Device,
+    force_law: ForceLaw,
+    kernel: &str,
+    bind_group_layouts: &[&wgpu::BindGroupLayout],
+) -> (wgpu::ShaderModule, wgpu::ComputePipeline) {
+    let compute_module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+        label: Some("Compute Module"),
+        source: wgpu::ShaderSource::Wgsl(
+            force_law
+                .kernel_source(kernel, include_str!("shaders/block.wgsl"))
+                .into(),
+        ),
+    });
+    let compute_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Compute Pipeline Layout"),
+        bind_group_layouts,
+        push_constant_ranges: &[],
+    });
+    let compute_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("Compute Pipeline"),
+        layout: Some(&compute_pipeline_layout),
+        module: &compute_module,
+        entry_point: "main",
+    });
+    (compute_module, compute_pipeline)
+}
+
+/// Staging buffer for reading back `capacity` particles, unless the primary buffers can be
+/// mapped directly.
+fn create_read_buffer(
+    device: &wgpu::Device,
+    capacity: u32,
+    mappable_primary_buffers: bool,
+) -> Option<wgpu::Buffer> {
+    match mappable_primary_buffers {
+        true => None,
+        false => Some(device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Particle Read Buffer"),
+            size: particle_bytes(capacity.max(1)),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })),
+    }
+}
+
+/// Copies the first `particle_num` particles out of `buffer`. If the buffer isn't mappable it is
+/// first copied into `staging`, which must be a `MAP_READ` buffer at least as large.
+fn read_particle_buffer(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    buffer: &wgpu::Buffer,
+    staging: Option<&wgpu::Buffer>,
+    particle_num: u32,
+) -> Vec<Particle> {
+    let size = particle_bytes(particle_num);
+    let mapped_buffer = match staging {
+        Some(staging) => {
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Particle Readback Command"),
+            });
+            encoder.copy_buffer_to_buffer(buffer, 0, staging, 0, size);
+            queue.submit(Some(encoder.finish()));
+            staging
+        }
+        None => buffer,
+    };
+    let slice = mapped_buffer.slice(..size);
+    let map_future = slice.map_async(wgpu::MapMode::Read);
+    device.poll(wgpu::Maintain::Wait);
+    pollster::block_on(map_future).unwrap();
+    let mut particles: Vec<Particle> = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+    mapped_buffer.unmap();
+    sort_by_id(&mut particles);
+    particles
+}
+
 pub trait Simulator {
     fn new(
         device: &wgpu::Device,
@@ -81,10 +312,142 @@ pub trait Simulator {
     where
         Self: Sized;
     fn encode(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> wgpu::CommandEncoder;
-    fn dest_particle_slice(&self) -> wgpu::BufferSlice;
+    fn dest_particle_slice(&self) -> wgpu::BufferSlice<'_>;
     fn sim_params(&self) -> SimParams;
 
+    /// Reads the particle state produced by the most recent step back from the GPU, in ID
+    /// order. Blocks until all submitted work has finished.
+    fn read_particles(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<Particle>;
+
+    /// Replaces the particle state the next step starts from. Fails unless `particles` holds
+    /// exactly `sim_params().particle_num` particles, see `replace_particles` for changing the
+    /// count.
+    fn write_particles(
+        &mut self,
+        queue: &wgpu::Queue,
+        particles: &[Particle],
+    ) -> anyhow::Result<()>;
+
+    /// Replaces `g`, `e` and `dt` for all following steps. The uniform write is queued, so steps
+    /// that were already submitted keep the old values. `particle_num` is ignored since the
+    /// particle count can change underneath the caller, see `replace_particles` for changing it.
+    fn set_sim_params(&mut self, queue: &wgpu::Queue, sim_params: SimParams);
+
+    /// Replaces the species table used for per-species softening for all following steps.
+    /// Fails if more than `MAX_SPECIES` are given.
+    fn set_species(&mut self, queue: &wgpu::Queue, species: &[Species]) -> anyhow::Result<()>;
+
+    /// Replaces the simulator specific parameters (e.g. `theta` for `TreeSim`) for all
+    /// following steps. Fails if `add_params` belongs to a different simulator.
+    fn set_add_params(&mut self, queue: &wgpu::Queue, add_params: AddParams)
+        -> anyhow::Result<()>;
+
+    /// Smallest timestep allowed by `criteria` for the particle state produced by the most
+    /// recent step, evaluated on the GPU. Blocks until all submitted work has finished.
+    fn min_timestep(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        criteria: &AdaptiveTimestep,
+    ) -> f32;
+
+    /// Switches between one global timestep (`None`) and power-of-two block timesteps. While
+    /// block timesteps are enabled every `encode` advances a single substep of
+    /// `dt / 2^max_level`, so `dt` should be left fixed (don't combine with `AdaptiveTimestep`).
+    fn set_block_timesteps(
+        &mut self,
+        device: &wgpu::Device,
+        block_timesteps: Option<BlockTimesteps>,
+    );
+
+    /// Replaces the pairwise interaction evaluated by the force kernels for all following steps.
+    /// The kernels are recompiled with the new law. Fails if `force_law` doesn't validate.
+    fn set_force_law(&mut self, device: &wgpu::Device, force_law: ForceLaw) -> anyhow::Result<()>;
+
+    /// Enables first post-Newtonian corrections with the given speed of light, or disables them
+    /// (`None`). Fails for simulators without 1PN support, currently everything except
+    /// `NaiveSim`.
+    fn set_post_newtonian(
+        &mut self,
+        _device: &wgpu::Device,
+        _post_newtonian: Option<PostNewtonian>,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("post-Newtonian corrections are only supported by NaiveSim")
+    }
+
+    /// Switches between the leapfrog kernels and the Wisdom-Holman mapping (`Some`), which
+    /// advances bodies on Kepler orbits around a central body. Fails for simulators without
+    /// Wisdom-Holman support, currently everything except `NaiveSim`.
+    fn set_wisdom_holman(
+        &mut self,
+        _device: &wgpu::Device,
+        _wisdom_holman: Option<WisdomHolman>,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("the Wisdom-Holman integrator is only supported by NaiveSim")
+    }
+
+    /// Replaces the analytic background potentials added to the self-gravity for all following
+    /// steps. Fails if more than `MAX_EXTERNAL_POTENTIALS` are given.
+    fn set_external_potentials(
+        &mut self,
+        queue: &wgpu::Queue,
+        potentials: &[ExternalPotential],
+    ) -> anyhow::Result<()>;
+
+    /// Replaces the whole particle set, which may have a different size than before. Buffers
+    /// grow to twice their capacity when the particles don't fit. Updates `particle_num`. Fails,
+    /// leaving the simulation untouched, if the particles exceed the device's limits.
+    fn replace_particles(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        particles: &[Particle],
+    ) -> anyhow::Result<()>;
+
+    /// Appends `particles` (e.g. from spawners or inflow) before the next step. Their IDs should
+    /// not collide with existing ones.
+    fn add_particles(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        particles: &[Particle],
+    ) -> anyhow::Result<()> {
+        let mut all = self.read_particles(device, queue);
+        all.extend_from_slice(particles);
+        self.replace_particles(device, queue, &all)
+    }
+
+    /// Removes every particle whose ID is in `ids` before the next step.
+    fn remove_particles(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        ids: &[u32],
+    ) -> anyhow::Result<()> {
+        let ids: std::collections::HashSet<u32> = ids.iter().copied().collect();
+        let mut all = self.read_particles(device, queue);
+        all.retain(|p| !ids.contains(&p.id));
+        self.replace_particles(device, queue, &all)
+    }
+
+    /// Number of live particles, which can change with collisions, insertion and removal.
+    fn particle_count(&self) -> u32 {
+        self.sim_params().particle_num
+    }
+
+    /// Simulated time covered by one `encode`, `sim_params().dt` unless block timesteps are
+    /// enabled.
+    fn step_dt(&self) -> f32 {
+        self.sim_params().dt
+    }
+
     /// Optional Method that can be run while the GPU is executing code, helpful for resource
     /// cleanup
     fn cleanup(&mut self) {}
+
+    /// Optional Method for simulators that respond to window input (e.g. playback controls).
+    /// Returns whether the event was consumed.
+    fn input(&mut self, _event: &winit::event::WindowEvent) -> bool {
+        false
+    }
 }
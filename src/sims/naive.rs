@@ -1,17 +1,42 @@
-use std::borrow::Cow;
-
 use super::AddParams;
 use super::Particle;
 use super::SimParams;
 use super::Simulator;
-use anyhow::Result;
+use super::{block::BlockStepper, BlockTimesteps};
+use super::{post_newtonian::PostNewtonianStepper, PostNewtonian};
+use super::{wisdom_holman::WisdomHolmanStepper, WisdomHolman};
+use super::{environment::Environment, ExternalPotential, ForceLaw, Species};
+use super::{timestep::TimestepReduction, AdaptiveTimestep};
+use anyhow::{bail, ensure, Result};
+use log::warn;
 use wgpu::util::DeviceExt;
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct NaiveParams {
+    massive_num: u32,
+    _padding: [u32; 3],
+}
+
 pub struct NaiveSim {
     sim_params: SimParams,
+    sim_params_buffer: wgpu::Buffer,
+    naive_params_buffer: wgpu::Buffer,
     particle_bind_groups: Vec<wgpu::BindGroup>,
     particle_buffers: Vec<wgpu::Buffer>,
+    particle_read_buffer: Option<wgpu::Buffer>,
+    /// particles that fit into the particle buffers
+    capacity: u32,
+    mappable_primary_buffers: bool,
+    timestep_reduction: TimestepReduction,
+    compute_module: wgpu::ShaderModule,
+    compute_bind_group_layout: wgpu::BindGroupLayout,
+    environment: Environment,
     compute_pipeline: wgpu::ComputePipeline,
+    force_law: ForceLaw,
+    block_stepper: Option<BlockStepper>,
+    post_newtonian: Option<PostNewtonianStepper>,
+    wisdom_holman: Option<WisdomHolmanStepper>,
     work_group_count: u32,
     step_num: usize,
 }
@@ -21,20 +46,16 @@ impl Simulator for NaiveSim {
         device: &wgpu::Device,
         sim_params: SimParams,
         _add_params: AddParams,
-        _mappable_primary_buffers: bool,
+        mappable_primary_buffers: bool,
         init_fn: fn(&SimParams) -> Vec<Particle>,
     ) -> Result<Self> {
+        super::check_particle_limits(device, "NaiveSim", sim_params.particle_num.max(1))?;
         let sim_params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Sim Params Buffer"),
             contents: bytemuck::cast_slice(&[sim_params]),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let compute_module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-            label: Some("Compute Module"),
-            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("shaders/naive.wgsl"))),
-        });
-
         let compute_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("Compute Bind Group Layout"),
@@ -57,9 +78,9 @@ impl Simulator for NaiveSim {
                         ty: wgpu::BindingType::Buffer {
                             ty: wgpu::BufferBindingType::Storage { read_only: true },
                             has_dynamic_offset: false,
+                            // one particle, so the layout still fits once the buffers grow
                             min_binding_size: wgpu::BufferSize::new(
-                                (sim_params.particle_num as usize * std::mem::size_of::<Particle>())
-                                    as _,
+                                std::mem::size_of::<Particle>() as _,
                             ),
                         },
                         count: None,
@@ -71,8 +92,19 @@ impl Simulator for NaiveSim {
                             ty: wgpu::BufferBindingType::Storage { read_only: false },
                             has_dynamic_offset: false,
                             min_binding_size: wgpu::BufferSize::new(
-                                (sim_params.particle_num as usize * std::mem::size_of::<Particle>())
-                                    as _,
+                                std::mem::size_of::<Particle>() as _,
+                            ),
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: wgpu::BufferSize::new(
+                                std::mem::size_of::<NaiveParams>() as _,
                             ),
                         },
                         count: None,
@@ -80,80 +112,124 @@ impl Simulator for NaiveSim {
                 ],
             });
 
-        let compute_pipeline_layout =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Compute Pipeline Layout"),
-                bind_group_layouts: &[&compute_bind_group_layout],
-                push_constant_ranges: &[],
-            });
+        let environment = Environment::new(device);
+
+        let force_law = ForceLaw::default();
+        let (compute_module, compute_pipeline) = super::create_force_kernel(
+            device,
+            force_law,
+            include_str!("shaders/naive.wgsl"),
+            &[&compute_bind_group_layout, &environment.bind_group_layout],
+        );
 
-        let compute_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-            label: Some("Compute Pipeline"),
-            layout: Some(&compute_pipeline_layout),
-            module: &compute_module,
-            entry_point: "main",
+        let mut initial_particles = init_fn(&sim_params);
+        // only the massive prefix is iterated over as force sources
+        let naive_params = NaiveParams {
+            massive_num: super::partition_tracers(&mut initial_particles),
+            _padding: [0; 3],
+        };
+        let naive_params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Naive Params Buffer"),
+            contents: bytemuck::cast_slice(&[naive_params]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let initial_particles = init_fn(&sim_params);
-
-        let mut particle_buffers = Vec::<wgpu::Buffer>::new();
-        let mut particle_bind_groups = Vec::<wgpu::BindGroup>::new();
-        for i in 0..2 {
-            particle_buffers.push(
-                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some(&format!("Particle Buffer {}", i)),
-                    contents: bytemuck::cast_slice(&initial_particles),
-                    usage: wgpu::BufferUsages::VERTEX
-                        | wgpu::BufferUsages::STORAGE
-                        | wgpu::BufferUsages::COPY_DST,
-                }),
-            )
-        }
+        let capacity = sim_params.particle_num;
+        let particle_buffers = super::create_particle_buffers(
+            device,
+            &initial_particles,
+            capacity,
+            mappable_primary_buffers,
+        );
+        let particle_read_buffer =
+            super::create_read_buffer(device, capacity, mappable_primary_buffers);
+        let particle_bind_groups = Self::create_bind_groups(
+            device,
+            &compute_bind_group_layout,
+            &sim_params_buffer,
+            &naive_params_buffer,
+            &particle_buffers,
+        );
 
-        for i in 0..2 {
-            particle_bind_groups.push(device.create_bind_group(&wgpu::BindGroupDescriptor {
-                label: Some(&format!("Bind Group {}", i)),
-                layout: &compute_bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: sim_params_buffer.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: particle_buffers[i].as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 2,
-                        resource: particle_buffers[(i + 1) % 2].as_entire_binding(),
-                    },
-                ],
-            }));
-        }
+        let timestep_reduction =
+            TimestepReduction::new(device, &sim_params_buffer, &particle_buffers, capacity);
 
-        let work_group_count =
-            ((sim_params.particle_num as f32) / (super::PARTICLES_PER_GROUP as f32)).ceil() as u32;
+        let work_group_count = sim_params.particle_num.div_ceil(super::PARTICLES_PER_GROUP);
 
         Ok(Self {
             sim_params,
+            sim_params_buffer,
+            naive_params_buffer,
             particle_bind_groups,
             particle_buffers,
+            particle_read_buffer,
+            capacity,
+            mappable_primary_buffers,
+            timestep_reduction,
+            compute_module,
+            compute_bind_group_layout,
+            environment,
             compute_pipeline,
+            force_law,
+            block_stepper: None,
+            post_newtonian: None,
+            wisdom_holman: None,
             work_group_count,
             step_num: 0,
         })
     }
 
-    fn encode(&mut self, device: &wgpu::Device, _queue: &wgpu::Queue) -> wgpu::CommandEncoder {
+    fn encode(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> wgpu::CommandEncoder {
         let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("Compute and Render Command"),
         });
+        if let Some(block_stepper) = self.block_stepper.as_mut() {
+            block_stepper.encode(
+                &mut encoder,
+                queue,
+                &self.sim_params,
+                &[
+                    &self.particle_bind_groups[self.step_num % 2],
+                    &self.environment.bind_group,
+                ],
+                &self.particle_buffers[self.step_num % 2],
+                &self.particle_buffers[(self.step_num + 1) % 2],
+                self.work_group_count,
+            );
+            self.step_num += 1;
+            return encoder;
+        }
+        if let Some(wisdom_holman) = self.wisdom_holman.as_ref() {
+            wisdom_holman.encode(
+                &mut encoder,
+                &[
+                    &self.particle_bind_groups[self.step_num % 2],
+                    &self.environment.bind_group,
+                ],
+                self.work_group_count,
+            );
+            self.step_num += 1;
+            return encoder;
+        }
+        if let Some(post_newtonian) = self.post_newtonian.as_ref() {
+            post_newtonian.encode(
+                &mut encoder,
+                &[
+                    &self.particle_bind_groups[self.step_num % 2],
+                    &self.environment.bind_group,
+                ],
+                self.work_group_count,
+            );
+            self.step_num += 1;
+            return encoder;
+        }
         encoder.push_debug_group("n-body movement");
         {
             let mut cpass =
                 encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
             cpass.set_pipeline(&self.compute_pipeline);
             cpass.set_bind_group(0, &self.particle_bind_groups[self.step_num % 2], &[]);
+            cpass.set_bind_group(1, &self.environment.bind_group, &[]);
             cpass.dispatch(self.work_group_count, 1, 1);
         }
         encoder.pop_debug_group();
@@ -161,11 +237,320 @@ impl Simulator for NaiveSim {
         encoder
     }
 
-    fn dest_particle_slice(&self) -> wgpu::BufferSlice {
+    fn dest_particle_slice(&self) -> wgpu::BufferSlice<'_> {
         self.particle_buffers[(self.step_num + 1) % 2].slice(..)
     }
 
     fn sim_params(&self) -> SimParams {
         self.sim_params
     }
+
+    fn read_particles(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<Particle> {
+        super::read_particle_buffer(
+            device,
+            queue,
+            &self.particle_buffers[self.step_num % 2],
+            self.particle_read_buffer.as_ref(),
+            self.sim_params.particle_num,
+        )
+    }
+
+    fn write_particles(&mut self, queue: &wgpu::Queue, particles: &[Particle]) -> Result<()> {
+        ensure!(
+            particles.len() == self.sim_params.particle_num as usize,
+            "Got {} particles but the simulation holds {}, use replace_particles to change the \
+             count",
+            particles.len(),
+            self.sim_params.particle_num
+        );
+        let mut particles = particles.to_vec();
+        self.write_massive_num(queue, &mut particles);
+        queue.write_buffer(
+            &self.particle_buffers[self.step_num % 2],
+            0,
+            bytemuck::cast_slice(&particles),
+        );
+        Ok(())
+    }
+
+    fn replace_particles(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        particles: &[Particle],
+    ) -> anyhow::Result<()> {
+        let particle_num = particles.len() as u32;
+        let grow = particle_num > self.capacity;
+        if grow {
+            // doubling stops at the device limits, beyond them the particles don't fit at all
+            let capacity = super::grown_capacity(self.capacity, particle_num)
+                .min(super::max_particles(device).max(particle_num));
+            super::check_particle_limits(device, "NaiveSim", capacity)?;
+            self.capacity = capacity;
+        }
+        let mut particles = particles.to_vec();
+        self.write_massive_num(queue, &mut particles);
+        if grow {
+            self.particle_buffers = super::create_particle_buffers(
+                device,
+                &particles,
+                self.capacity,
+                self.mappable_primary_buffers,
+            );
+            self.particle_read_buffer =
+                super::create_read_buffer(device, self.capacity, self.mappable_primary_buffers);
+            self.particle_bind_groups = Self::create_bind_groups(
+                device,
+                &self.compute_bind_group_layout,
+                &self.sim_params_buffer,
+                &self.naive_params_buffer,
+                &self.particle_buffers,
+            );
+            self.timestep_reduction = TimestepReduction::new(
+                device,
+                &self.sim_params_buffer,
+                &self.particle_buffers,
+                self.capacity,
+            );
+            if let Some(block_stepper) = self.block_stepper.as_mut() {
+                block_stepper.resize(device, self.capacity);
+            }
+            if let Some(post_newtonian) = self.post_newtonian.as_mut() {
+                post_newtonian.resize(device, self.capacity);
+            }
+        } else {
+            queue.write_buffer(
+                &self.particle_buffers[self.step_num % 2],
+                0,
+                bytemuck::cast_slice(&particles),
+            );
+        }
+        self.sim_params.particle_num = particle_num;
+        queue.write_buffer(
+            &self.sim_params_buffer,
+            0,
+            bytemuck::cast_slice(&[self.sim_params]),
+        );
+        self.work_group_count = particle_num.div_ceil(super::PARTICLES_PER_GROUP);
+        Ok(())
+    }
+
+    fn set_sim_params(&mut self, queue: &wgpu::Queue, sim_params: SimParams) {
+        self.sim_params = SimParams {
+            particle_num: self.sim_params.particle_num,
+            ..sim_params
+        };
+        queue.write_buffer(
+            &self.sim_params_buffer,
+            0,
+            bytemuck::cast_slice(&[self.sim_params]),
+        );
+    }
+
+    fn set_add_params(&mut self, _queue: &wgpu::Queue, add_params: AddParams) -> Result<()> {
+        match add_params {
+            AddParams::NaiveSimParams => Ok(()),
+            _ => bail!("NaiveSim only accepts AddParams::NaiveSimParams"),
+        }
+    }
+
+    fn min_timestep(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        criteria: &AdaptiveTimestep,
+    ) -> f32 {
+        self.timestep_reduction.min_timestep(
+            device,
+            queue,
+            self.step_num % 2,
+            &self.sim_params,
+            criteria,
+        )
+    }
+
+    fn set_block_timesteps(
+        &mut self,
+        device: &wgpu::Device,
+        block_timesteps: Option<BlockTimesteps>,
+    ) {
+        if block_timesteps.is_some() && self.post_newtonian.take().is_some() {
+            warn!("Block timesteps disable post-Newtonian corrections");
+        }
+        if block_timesteps.is_some() && self.wisdom_holman.take().is_some() {
+            warn!("Block timesteps disable the Wisdom-Holman integrator");
+        }
+        self.block_stepper = block_timesteps.map(|config| {
+            BlockStepper::new(
+                device,
+                config,
+                &self.compute_module,
+                &[
+                    &self.compute_bind_group_layout,
+                    &self.environment.bind_group_layout,
+                ],
+                self.capacity,
+            )
+        });
+    }
+
+    fn set_force_law(&mut self, device: &wgpu::Device, force_law: ForceLaw) -> Result<()> {
+        force_law.validate()?;
+        if force_law == self.force_law {
+            return Ok(());
+        }
+        if force_law != ForceLaw::Newtonian && self.post_newtonian.take().is_some() {
+            warn!("Post-Newtonian corrections require ForceLaw::Newtonian, disabling them");
+        }
+        if force_law != ForceLaw::Newtonian && self.wisdom_holman.take().is_some() {
+            warn!("The Wisdom-Holman integrator requires ForceLaw::Newtonian, disabling it");
+        }
+        let bind_group_layouts = [
+            &self.compute_bind_group_layout,
+            &self.environment.bind_group_layout,
+        ];
+        (self.compute_module, self.compute_pipeline) = super::create_force_kernel(
+            device,
+            force_law,
+            include_str!("shaders/naive.wgsl"),
+            &bind_group_layouts,
+        );
+        if let Some(block_stepper) = self.block_stepper.as_mut() {
+            block_stepper.set_module(device, &self.compute_module, &bind_group_layouts);
+        }
+        self.force_law = force_law;
+        Ok(())
+    }
+
+    fn set_post_newtonian(
+        &mut self,
+        device: &wgpu::Device,
+        post_newtonian: Option<PostNewtonian>,
+    ) -> Result<()> {
+        let post_newtonian = match post_newtonian {
+            Some(config) => {
+                if self.force_law != ForceLaw::Newtonian {
+                    bail!("post-Newtonian corrections require ForceLaw::Newtonian");
+                }
+                if self.block_stepper.is_some() {
+                    bail!("post-Newtonian corrections can't be combined with block timesteps");
+                }
+                if self.wisdom_holman.is_some() {
+                    bail!("post-Newtonian corrections can't be combined with Wisdom-Holman");
+                }
+                Some(PostNewtonianStepper::new(
+                    device,
+                    config,
+                    &[
+                        &self.compute_bind_group_layout,
+                        &self.environment.bind_group_layout,
+                    ],
+                    self.capacity,
+                )?)
+            }
+            None => None,
+        };
+        self.post_newtonian = post_newtonian;
+        Ok(())
+    }
+
+    fn set_wisdom_holman(
+        &mut self,
+        device: &wgpu::Device,
+        wisdom_holman: Option<WisdomHolman>,
+    ) -> Result<()> {
+        let wisdom_holman = match wisdom_holman {
+            Some(config) => {
+                if self.force_law != ForceLaw::Newtonian {
+                    bail!("the Wisdom-Holman integrator requires ForceLaw::Newtonian");
+                }
+                if self.block_stepper.is_some() {
+                    bail!("the Wisdom-Holman integrator can't be combined with block timesteps");
+                }
+                if self.post_newtonian.is_some() {
+                    bail!("the Wisdom-Holman integrator can't be combined with 1PN corrections");
+                }
+                Some(WisdomHolmanStepper::new(
+                    device,
+                    config,
+                    &[
+                        &self.compute_bind_group_layout,
+                        &self.environment.bind_group_layout,
+                    ],
+                ))
+            }
+            None => None,
+        };
+        self.wisdom_holman = wisdom_holman;
+        Ok(())
+    }
+
+    fn set_external_potentials(
+        &mut self,
+        queue: &wgpu::Queue,
+        potentials: &[ExternalPotential],
+    ) -> anyhow::Result<()> {
+        self.environment.write_potentials(queue, potentials)
+    }
+
+    fn set_species(&mut self, queue: &wgpu::Queue, species: &[Species]) -> anyhow::Result<()> {
+        self.environment.write_species(queue, species)
+    }
+
+    fn step_dt(&self) -> f32 {
+        match &self.block_stepper {
+            Some(block_stepper) => block_stepper.substep_dt(&self.sim_params),
+            None => self.sim_params.dt,
+        }
+    }
+}
+
+impl NaiveSim {
+    /// Moves tracers behind the massive particles and uploads how many sources there are.
+    fn write_massive_num(&self, queue: &wgpu::Queue, particles: &mut [Particle]) {
+        let naive_params = NaiveParams {
+            massive_num: super::partition_tracers(particles),
+            _padding: [0; 3],
+        };
+        queue.write_buffer(
+            &self.naive_params_buffer,
+            0,
+            bytemuck::cast_slice(&[naive_params]),
+        );
+    }
+
+    fn create_bind_groups(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        sim_params_buffer: &wgpu::Buffer,
+        naive_params_buffer: &wgpu::Buffer,
+        particle_buffers: &[wgpu::Buffer],
+    ) -> Vec<wgpu::BindGroup> {
+        (0..2)
+            .map(|i| {
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some(&format!("Bind Group {}", i)),
+                    layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: sim_params_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: particle_buffers[i].as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: particle_buffers[(i + 1) % 2].as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 3,
+                            resource: naive_params_buffer.as_entire_binding(),
+                        },
+                    ],
+                })
+            })
+            .collect()
+    }
 }
@@ -0,0 +1,502 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+};
+
+use anyhow::{bail, ensure};
+use rayon::prelude::*;
+
+use super::{
+    tree::{Octant, SPLIT_DEPTH},
+    Particle, TreeSim,
+};
+
+/// Size of the node stack in `tree.wgsl`'s traversal.
+pub const TRAVERSAL_STACK_SIZE: usize = 64;
+
+/// Read-only octree over the massive particles of a particle set, built the same way `TreeSim`
+/// builds its tree every step, including the shallower splits for trees that would overflow the
+/// traversal stack. Node 0 is the root, a cube of width `root_width` centred at the origin.
+/// Leaves hold exactly one particle.
+///
+/// Spatial queries only find massive particles (tracers aren't in the tree) and return their
+/// indices in the built-from set. The batch variants run on all threads, query points in
+/// `locality_order` keep each thread on one part of the tree.
+pub struct Octree {
+    nodes: Vec<Octant>,
+    root_width: f32,
+    split_depth: u32,
+    particle_num: usize,
+}
+
+/// A copy of one tree node.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OctreeNode {
+    pub center_of_mass: [f32; 3],
+    pub mass: f32,
+    pub charge: f32,
+    /// Massive particles below this node
+    pub bodies: u32,
+    /// Child node indices by octant, `None` for empty octants and for leaves
+    pub children: [Option<u32>; 8],
+    /// Index of the particle in the built-from set, leaves only
+    pub particle: Option<u32>,
+}
+
+/// A node with its bounding box. Leaves are boxes of zero width around their particle, other
+/// boxes are widened slightly so that f32 rounding of the node centres can't exclude particles.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct BoundedNode {
+    pub(crate) ix: usize,
+    pub(crate) center: [f32; 3],
+    pub(crate) half_width: f32,
+    pub(crate) bodies: u32,
+}
+
+impl BoundedNode {
+    pub(crate) fn is_leaf(&self) -> bool {
+        self.bodies == 1
+    }
+
+    /// Distance from `point` to the closest point of the box.
+    fn distance_to(&self, point: &[f32; 3]) -> f32 {
+        (0..3)
+            .map(|k| ((point[k] - self.center[k]).abs() - self.half_width).max(0.0))
+            .map(|d| d * d)
+            .sum::<f32>()
+            .sqrt()
+    }
+
+    /// Smallest and largest distance between points of the two boxes.
+    pub(crate) fn separation(&self, other: &BoundedNode) -> (f64, f64) {
+        let mut near = 0.0;
+        let mut far = 0.0;
+        for k in 0..3 {
+            let offset = (self.center[k] as f64 - other.center[k] as f64).abs();
+            let extent = self.half_width as f64 + other.half_width as f64;
+            near += (offset - extent).max(0.0).powi(2);
+            far += (offset + extent).powi(2);
+        }
+        (near.sqrt(), far.sqrt())
+    }
+
+    fn intersects(&self, min: &[f32; 3], max: &[f32; 3]) -> bool {
+        (0..3).all(|k| {
+            self.center[k] + self.half_width >= min[k] && self.center[k] - self.half_width <= max[k]
+        })
+    }
+}
+
+/// Candidate of a nearest neighbour search, ordered by distance only.
+#[derive(Copy, Clone)]
+struct Candidate<T>(f32, T);
+
+impl<T> PartialEq for Candidate<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Candidate<T> {}
+
+impl<T> PartialOrd for Candidate<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Candidate<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OctreeStats {
+    pub nodes: usize,
+    pub leaves: usize,
+    /// Node count per depth, the root being depth 0
+    pub depth_histogram: Vec<usize>,
+    /// Internal node count by number of occupied children, `occupancy[k]` counting nodes with
+    /// `k + 1` children
+    pub occupancy: [usize; 8],
+    /// Stack size the traversal in `tree.wgsl` needs when it opens every node, to be compared
+    /// with `TRAVERSAL_STACK_SIZE`
+    pub max_stack_depth: usize,
+    /// Nodes over the `particle_num * 4` nodes `TreeSim` allocates
+    pub buffer_fill: f32,
+}
+
+impl Octree {
+    pub fn build(particles: &[Particle]) -> Self {
+        let arena = bumpalo::Bump::new();
+        // same allocation as `TreeSim`, doubled until the tree fits
+        let mut capacity = (particles.len() * 4).max(1);
+        let mut split_depth = SPLIT_DEPTH;
+        loop {
+            let mut nodes = vec![Octant::default(); capacity];
+            match TreeSim::build_octree(particles, &mut nodes, &arena, split_depth) {
+                Some((len, root_width)) => {
+                    nodes.truncate(len);
+                    let too_deep = TreeSim::traversal_stack_depth(&nodes) > TRAVERSAL_STACK_SIZE;
+                    if !too_deep || split_depth == 0 {
+                        return Octree {
+                            nodes,
+                            root_width,
+                            split_depth,
+                            particle_num: particles.len(),
+                        };
+                    }
+                    split_depth /= 2;
+                }
+                None => capacity *= 2,
+            }
+        }
+    }
+
+    pub fn root_width(&self) -> f32 {
+        self.root_width
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Whether there are no massive particles.
+    pub fn is_empty(&self) -> bool {
+        self.nodes[0].bodies == 0
+    }
+
+    pub fn node(&self, ix: usize) -> OctreeNode {
+        let octant = &self.nodes[ix];
+        let mut children = [None; 8];
+        for (child, child_ix) in octant.children() {
+            children[child] = Some(child_ix);
+        }
+        OctreeNode {
+            center_of_mass: octant.cog,
+            mass: octant.mass,
+            charge: octant.charge,
+            bodies: octant.bodies,
+            children,
+            particle: (octant.bodies == 1).then_some(octant.first_child),
+        }
+    }
+
+    pub fn stats(&self) -> OctreeStats {
+        let mut depth_histogram = Vec::new();
+        let mut occupancy = [0; 8];
+        let mut leaves = 0;
+        let mut stack = vec![(0usize, 0usize)];
+        while let Some((ix, depth)) = stack.pop() {
+            if depth_histogram.len() <= depth {
+                depth_histogram.resize(depth + 1, 0);
+            }
+            depth_histogram[depth] += 1;
+            let node = self.node(ix);
+            if node.particle.is_some() {
+                leaves += 1;
+                continue;
+            }
+            let children: Vec<u32> = node.children.iter().flatten().copied().collect();
+            if let Some(count) = children.len().checked_sub(1) {
+                occupancy[count] += 1;
+            }
+            stack.extend(children.iter().map(|&child| (child as usize, depth + 1)));
+        }
+        OctreeStats {
+            nodes: self.nodes.len(),
+            leaves,
+            depth_histogram,
+            occupancy,
+            max_stack_depth: TreeSim::traversal_stack_depth(&self.nodes),
+            buffer_fill: self.nodes.len() as f32 / (self.particle_num * 4).max(1) as f32,
+        }
+    }
+
+    /// Checks that every massive particle of `particles` (the set the tree was built from) is in
+    /// exactly one leaf, that internal nodes sum up their children's bodies, masses, charges and
+    /// centres of mass (to f32 accumulation accuracy), that child indices are in range and
+    /// reference every node at most once, that nodes are flagged degenerate exactly when their
+    /// children keep their width and that the traversal fits `TRAVERSAL_STACK_SIZE`.
+    pub fn validate(&self, particles: &[Particle]) -> anyhow::Result<()> {
+        ensure!(
+            particles.len() == self.particle_num,
+            "tree was built from {} particles, not {}",
+            self.particle_num,
+            particles.len()
+        );
+        let stack_depth = TreeSim::traversal_stack_depth(&self.nodes);
+        ensure!(
+            stack_depth <= TRAVERSAL_STACK_SIZE,
+            "traversal needs a stack of {} entries but tree.wgsl has {}",
+            stack_depth,
+            TRAVERSAL_STACK_SIZE
+        );
+        let massive = particles.iter().filter(|p| !p.is_tracer()).count();
+        ensure!(
+            self.nodes[0].bodies as usize == massive,
+            "root holds {} bodies instead of {}",
+            self.nodes[0].bodies,
+            massive
+        );
+        const TOLERANCE: f64 = 1e-3;
+        let mut leaf_counts = vec![0u32; particles.len()];
+        let mut referenced = vec![false; self.nodes.len()];
+        referenced[0] = true;
+        let mut stack = vec![(0usize, self.root_width)];
+        while let Some((ix, width)) = stack.pop() {
+            let octant = &self.nodes[ix];
+            match octant.bodies {
+                0 => ensure!(ix == 0, "node {} is empty", ix),
+                1 => {
+                    ensure!(octant.child_mask == 0, "leaf {} has children", ix);
+                    let particle_ix = octant.first_child as usize;
+                    ensure!(
+                        particle_ix < particles.len(),
+                        "leaf {} references particle {} of {}",
+                        ix,
+                        particle_ix,
+                        particles.len()
+                    );
+                    let p = &particles[particle_ix];
+                    ensure!(!p.is_tracer(), "leaf {} holds tracer {}", ix, particle_ix);
+                    ensure!(
+                        octant.cog == p.position && octant.mass == p.mass,
+                        "leaf {} doesn't match particle {}",
+                        ix,
+                        particle_ix
+                    );
+                    leaf_counts[particle_ix] += 1;
+                }
+                _ => {
+                    let (_, child_width) = TreeSim::child_bounds(
+                        &[0.0; 3],
+                        width,
+                        self.root_width,
+                        self.split_depth,
+                        0,
+                    );
+                    ensure!(
+                        octant.is_degenerate() == (child_width == width),
+                        "node {} has the wrong degenerate flag",
+                        ix
+                    );
+                    let mut bodies = 0;
+                    let mut mass = 0.0;
+                    let mut charge = 0.0;
+                    let mut moment = [0.0f64; 3];
+                    for (_, child) in octant.children() {
+                        let child = child as usize;
+                        ensure!(
+                            child < self.nodes.len(),
+                            "node {} references child {} of {}",
+                            ix,
+                            child,
+                            self.nodes.len()
+                        );
+                        if referenced[child] {
+                            bail!("node {} is referenced more than once", child);
+                        }
+                        referenced[child] = true;
+                        let c = &self.nodes[child];
+                        bodies += c.bodies;
+                        mass += c.mass as f64;
+                        charge += c.charge as f64;
+                        for (k, m) in moment.iter_mut().enumerate() {
+                            *m += c.mass as f64 * c.cog[k] as f64;
+                        }
+                        stack.push((child, child_width));
+                    }
+                    ensure!(
+                        bodies == octant.bodies,
+                        "node {} holds {} bodies but its children {}",
+                        ix,
+                        octant.bodies,
+                        bodies
+                    );
+                    ensure!(
+                        (octant.mass as f64 - mass).abs() <= TOLERANCE * mass,
+                        "node {} has mass {} but its children {}",
+                        ix,
+                        octant.mass,
+                        mass
+                    );
+                    let charge_scale = self.nodes[0].charge.abs().max(1.0) as f64;
+                    ensure!(
+                        (octant.charge as f64 - charge).abs() <= TOLERANCE * charge_scale,
+                        "node {} has charge {} but its children {}",
+                        ix,
+                        octant.charge,
+                        charge
+                    );
+                    for (k, moment) in moment.iter().enumerate() {
+                        let offset = octant.cog[k] as f64 - moment / mass;
+                        ensure!(
+                            offset.abs() <= TOLERANCE * self.root_width as f64,
+                            "node {} has its centre of mass off by {} along axis {}",
+                            ix,
+                            offset,
+                            k
+                        );
+                    }
+                }
+            }
+        }
+        for (ix, p) in particles.iter().enumerate() {
+            let expected = !p.is_tracer() as u32;
+            ensure!(
+                leaf_counts[ix] == expected,
+                "particle {} is in {} leaves instead of {}",
+                ix,
+                leaf_counts[ix],
+                expected
+            );
+        }
+        Ok(())
+    }
+
+    /// Indices of the massive particles in depth-first leaf order, the order `TreeSim` sorts
+    /// its particles into.
+    pub fn locality_order(&self) -> Vec<u32> {
+        let mut order = vec![0; self.nodes[0].bodies as usize];
+        if !order.is_empty() {
+            TreeSim::write_in_tree_order(self.nodes[0], &|ix| ix, &mut order, &self.nodes);
+        }
+        order
+    }
+
+    /// The `k` massive particles closest to `point` as `(index, distance)` pairs, closest first.
+    /// A particle at `point` is its own nearest neighbour.
+    pub fn nearest(&self, point: [f32; 3], k: usize) -> Vec<(u32, f32)> {
+        if k == 0 || self.is_empty() {
+            return Vec::new();
+        }
+        let mut found: BinaryHeap<Candidate<u32>> = BinaryHeap::with_capacity(k + 1);
+        // nodes by distance of their box, visited closest first until the box is further than
+        // the k-th neighbour found so far
+        let root = self.root_node();
+        let mut queue = BinaryHeap::new();
+        queue.push(Reverse(Candidate(root.distance_to(&point), root)));
+        while let Some(Reverse(Candidate(distance, node))) = queue.pop() {
+            if found.len() == k && distance > found.peek().unwrap().0 {
+                break;
+            }
+            if node.is_leaf() {
+                found.push(Candidate(distance, self.nodes[node.ix].first_child));
+                if found.len() > k {
+                    found.pop();
+                }
+                continue;
+            }
+            for child in self.child_nodes(node) {
+                queue.push(Reverse(Candidate(child.distance_to(&point), child)));
+            }
+        }
+        found
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Candidate(distance, particle)| (particle, distance))
+            .collect()
+    }
+
+    /// Massive particles no further than `radius` from `point`.
+    pub fn within_radius(&self, point: [f32; 3], radius: f32) -> Vec<u32> {
+        let mut found = Vec::new();
+        self.visit_leaves(
+            |node| node.distance_to(&point) <= radius,
+            |particle| found.push(particle),
+        );
+        found
+    }
+
+    /// Massive particles inside the axis-aligned box from `min` to `max` (inclusive).
+    pub fn in_box(&self, min: [f32; 3], max: [f32; 3]) -> Vec<u32> {
+        let mut found = Vec::new();
+        self.visit_leaves(
+            |node| node.intersects(&min, &max),
+            |particle| found.push(particle),
+        );
+        found
+    }
+
+    pub fn nearest_batch(&self, points: &[[f32; 3]], k: usize) -> Vec<Vec<(u32, f32)>> {
+        points.par_iter().map(|&p| self.nearest(p, k)).collect()
+    }
+
+    pub fn within_radius_batch(&self, points: &[[f32; 3]], radius: f32) -> Vec<Vec<u32>> {
+        points
+            .par_iter()
+            .map(|&p| self.within_radius(p, radius))
+            .collect()
+    }
+
+    /// One result per `(min, max)` box.
+    pub fn in_box_batch(&self, boxes: &[([f32; 3], [f32; 3])]) -> Vec<Vec<u32>> {
+        boxes
+            .par_iter()
+            .map(|&(min, max)| self.in_box(min, max))
+            .collect()
+    }
+
+    pub(crate) fn root_node(&self) -> BoundedNode {
+        self.bounded_node(0, [0.0; 3], self.root_width)
+    }
+
+    pub(crate) fn child_nodes(&self, node: BoundedNode) -> impl Iterator<Item = BoundedNode> + '_ {
+        // undo the rounding slack before splitting
+        let width = (node.half_width - self.rounding_slack()) * 2.0;
+        self.nodes[node.ix]
+            .children()
+            .map(move |(child, child_ix)| {
+                let (center, width) = TreeSim::child_bounds(
+                    &node.center,
+                    width,
+                    self.root_width,
+                    self.split_depth,
+                    child,
+                );
+                self.bounded_node(child_ix as usize, center, width)
+            })
+    }
+
+    fn bounded_node(&self, ix: usize, center: [f32; 3], width: f32) -> BoundedNode {
+        let octant = &self.nodes[ix];
+        match octant.bodies {
+            1 => BoundedNode {
+                ix,
+                center: octant.cog,
+                half_width: 0.0,
+                bodies: 1,
+            },
+            bodies => BoundedNode {
+                ix,
+                center,
+                half_width: width / 2.0 + self.rounding_slack(),
+                bodies,
+            },
+        }
+    }
+
+    fn rounding_slack(&self) -> f32 {
+        self.root_width * f32::EPSILON * 64.0
+    }
+
+    /// Calls `visit` with the particle of every leaf whose box and ancestors' boxes pass
+    /// `enter`.
+    fn visit_leaves(&self, enter: impl Fn(&BoundedNode) -> bool, mut visit: impl FnMut(u32)) {
+        if self.is_empty() {
+            return;
+        }
+        let mut stack = vec![self.root_node()];
+        while let Some(node) = stack.pop() {
+            if !enter(&node) {
+                continue;
+            }
+            match node.is_leaf() {
+                true => visit(self.nodes[node.ix].first_child),
+                false => stack.extend(self.child_nodes(node)),
+            }
+        }
+    }
+}
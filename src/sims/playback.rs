@@ -0,0 +1,311 @@
+use std::time::Instant;
+
+use anyhow::bail;
+use log::{error, info, warn};
+use rayon::prelude::*;
+use winit::event::{ElementState, KeyboardInput, VirtualKeyCode, WindowEvent};
+
+use crate::trajectory::{Frame, TrajectoryReader};
+
+use super::{
+    AdaptiveTimestep, AddParams, BlockTimesteps, ExternalPotential, ForceLaw, Particle, SimParams,
+    Simulator, Species,
+};
+
+/// Recorded frames shown per second at a playback speed of 1.0
+const PLAYBACK_FPS: f64 = 30.0;
+
+/// Replays a recorded trajectory instead of computing physics. Positions and velocities are
+/// linearly interpolated between neighbouring frames.
+pub struct PlaybackSim {
+    reader: TrajectoryReader,
+    sim_params: SimParams,
+    particle_buffer: wgpu::Buffer,
+    /// most recently read frames, keyed by frame index
+    frame_cache: Vec<(usize, Frame)>,
+    interpolated: Vec<Particle>,
+    /// playback position in (fractional) frames
+    position: f64,
+    speed: f64,
+    playing: bool,
+    last_update: Option<Instant>,
+}
+
+impl Simulator for PlaybackSim {
+    fn new(
+        device: &wgpu::Device,
+        _sim_params: SimParams,
+        add_params: AddParams,
+        _mappable_primary_buffers: bool,
+        _init_fn: fn(&SimParams) -> Vec<Particle>,
+    ) -> anyhow::Result<Self> {
+        let path = match add_params {
+            AddParams::PlaybackParams { path } => path,
+            _ => bail!("PlaybackSim requires AddParams::PlaybackParams"),
+        };
+        let reader = TrajectoryReader::open(path)?;
+        if reader.frame_count() == 0 {
+            bail!("Trajectory contains no frames");
+        }
+
+        let particle_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Playback Particle Buffer"),
+            size: (std::mem::size_of::<Particle>() as u64 * reader.max_particle_num() as u64)
+                .max(std::mem::size_of::<Particle>() as u64),
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Ok(Self {
+            sim_params: reader.sim_params(),
+            reader,
+            particle_buffer,
+            frame_cache: Vec::with_capacity(2),
+            interpolated: Vec::new(),
+            position: 0.0,
+            speed: 1.0,
+            playing: true,
+            last_update: None,
+        })
+    }
+
+    fn encode(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> wgpu::CommandEncoder {
+        let now = Instant::now();
+        if let (true, Some(last)) = (self.playing, self.last_update) {
+            self.position += (now - last).as_secs_f64() * PLAYBACK_FPS * self.speed;
+        }
+        self.last_update = Some(now);
+
+        let last_frame = (self.reader.frame_count() - 1) as f64;
+        if self.position >= last_frame && self.playing {
+            info!("Reached end of trajectory");
+            self.playing = false;
+        }
+        self.position = self.position.clamp(0.0, last_frame);
+
+        if let Err(e) = self.interpolate() {
+            // keep showing the last good frame
+            error!("Failed to read trajectory: {:#}", e);
+            self.playing = false;
+        }
+        queue.write_buffer(
+            &self.particle_buffer,
+            0,
+            bytemuck::cast_slice(&self.interpolated),
+        );
+
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Playback Render Command"),
+        })
+    }
+
+    fn dest_particle_slice(&self) -> wgpu::BufferSlice<'_> {
+        self.particle_buffer.slice(..)
+    }
+
+    fn sim_params(&self) -> SimParams {
+        SimParams {
+            particle_num: self.interpolated.len() as u32,
+            ..self.sim_params
+        }
+    }
+
+    fn read_particles(&self, _device: &wgpu::Device, _queue: &wgpu::Queue) -> Vec<Particle> {
+        let mut particles = self.interpolated.clone();
+        super::sort_by_id(&mut particles);
+        particles
+    }
+
+    fn write_particles(
+        &mut self,
+        _queue: &wgpu::Queue,
+        _particles: &[Particle],
+    ) -> anyhow::Result<()> {
+        warn!("PlaybackSim ignores written particles");
+        Ok(())
+    }
+
+    fn replace_particles(
+        &mut self,
+        _device: &wgpu::Device,
+        _queue: &wgpu::Queue,
+        _particles: &[Particle],
+    ) -> anyhow::Result<()> {
+        warn!("PlaybackSim can't replace recorded particles");
+        Ok(())
+    }
+
+    fn set_sim_params(&mut self, _queue: &wgpu::Queue, _sim_params: SimParams) {
+        warn!("PlaybackSim ignores simulation parameters");
+    }
+
+    fn set_species(&mut self, _queue: &wgpu::Queue, _species: &[Species]) -> anyhow::Result<()> {
+        // only affects forces, colours are handled by the renderer
+        Ok(())
+    }
+
+    fn set_add_params(
+        &mut self,
+        _queue: &wgpu::Queue,
+        add_params: AddParams,
+    ) -> anyhow::Result<()> {
+        match add_params {
+            AddParams::PlaybackParams { .. } => {
+                bail!("PlaybackSim can't switch trajectories at runtime")
+            }
+            _ => bail!("PlaybackSim only accepts AddParams::PlaybackParams"),
+        }
+    }
+
+    fn min_timestep(
+        &self,
+        _device: &wgpu::Device,
+        _queue: &wgpu::Queue,
+        _criteria: &AdaptiveTimestep,
+    ) -> f32 {
+        self.sim_params.dt
+    }
+
+    fn set_block_timesteps(
+        &mut self,
+        _device: &wgpu::Device,
+        _block_timesteps: Option<BlockTimesteps>,
+    ) {
+        warn!("PlaybackSim ignores block timesteps");
+    }
+
+    fn set_force_law(
+        &mut self,
+        _device: &wgpu::Device,
+        _force_law: ForceLaw,
+    ) -> anyhow::Result<()> {
+        warn!("PlaybackSim ignores force laws");
+        Ok(())
+    }
+
+    fn set_external_potentials(
+        &mut self,
+        _queue: &wgpu::Queue,
+        _potentials: &[ExternalPotential],
+    ) -> anyhow::Result<()> {
+        bail!("PlaybackSim can't change the recorded forces")
+    }
+
+    fn input(&mut self, event: &WindowEvent) -> bool {
+        let keycode = match event {
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(keycode),
+                        ..
+                    },
+                ..
+            } => *keycode,
+            _ => return false,
+        };
+        let last_frame = (self.reader.frame_count() - 1) as f64;
+        match keycode {
+            VirtualKeyCode::Space => {
+                if self.position >= last_frame {
+                    self.position = 0.0;
+                }
+                self.playing = !self.playing;
+            }
+            VirtualKeyCode::Comma => {
+                self.playing = false;
+                self.position = (self.position.ceil() - 1.0).max(0.0);
+            }
+            VirtualKeyCode::Period => {
+                self.playing = false;
+                self.position = (self.position.floor() + 1.0).min(last_frame);
+            }
+            VirtualKeyCode::LBracket => self.speed /= 2.0,
+            VirtualKeyCode::RBracket => self.speed *= 2.0,
+            VirtualKeyCode::Home => self.position = 0.0,
+            VirtualKeyCode::End => self.position = last_frame,
+            _ => return false,
+        }
+        info!(
+            "Playback {} at frame {:.2}/{} ({}x)",
+            if self.playing { "playing" } else { "paused" },
+            self.position,
+            last_frame,
+            self.speed
+        );
+        true
+    }
+}
+
+impl PlaybackSim {
+    /// Fills `interpolated` with the particle state at the current playback position.
+    fn interpolate(&mut self) -> anyhow::Result<()> {
+        let lower = self.position.floor() as usize;
+        let upper = (lower + 1).min(self.reader.frame_count() - 1);
+        let t = (self.position - lower as f64) as f32;
+        self.cache_frames(lower, upper)?;
+        let a = &cached_frame(&self.frame_cache, lower).particles;
+        let b = &cached_frame(&self.frame_cache, upper).particles;
+
+        self.interpolated.clear();
+        if a.len() != b.len() || t == 0.0 {
+            // frames can't be paired up, snap to the earlier one
+            self.interpolated.extend_from_slice(a);
+            return Ok(());
+        }
+        a.par_iter()
+            .zip(b.par_iter())
+            .map(|(pa, pb)| match pa.id == pb.id {
+                true => Particle {
+                    position: lerp3(pa.position, pb.position, t),
+                    velocity: lerp3(pa.velocity, pb.velocity, t),
+                    acceleration: lerp3(pa.acceleration, pb.acceleration, t),
+                    mass: pa.mass + (pb.mass - pa.mass) * t,
+                    id: pa.id,
+                    level: pa.level,
+                    radius: pa.radius,
+                    species: pa.species,
+                    charge: pa.charge,
+                    potential: pa.potential + (pb.potential - pa.potential) * t,
+                },
+                // frames weren't written in ID order
+                false => *pa,
+            })
+            .collect_into_vec(&mut self.interpolated);
+        Ok(())
+    }
+
+    /// Makes sure exactly the frames `lower` and `upper` are held in the cache.
+    fn cache_frames(&mut self, lower: usize, upper: usize) -> anyhow::Result<()> {
+        self.frame_cache
+            .retain(|(ix, _)| *ix == lower || *ix == upper);
+        for ix in [lower, upper] {
+            if !self
+                .frame_cache
+                .iter()
+                .any(|(cached_ix, _)| *cached_ix == ix)
+            {
+                let frame = self.reader.read_frame(ix)?;
+                self.frame_cache.push((ix, frame));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn cached_frame(frame_cache: &[(usize, Frame)], ix: usize) -> &Frame {
+    &frame_cache
+        .iter()
+        .find(|(cached_ix, _)| *cached_ix == ix)
+        .unwrap()
+        .1
+}
+
+#[inline]
+fn lerp3(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [
+        a[0] + (b[0] - a[0]) * t,
+        a[1] + (b[1] - a[1]) * t,
+        a[2] + (b[2] - a[2]) * t,
+    ]
+}
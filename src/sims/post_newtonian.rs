@@ -0,0 +1,205 @@
+use anyhow::{ensure, Result};
+use wgpu::util::DeviceExt;
+
+use super::ForceLaw;
+
+/// First post-Newtonian (Einstein-Infeld-Hoffmann) corrections to the Newtonian pair forces.
+/// The velocity dependent terms make the kick implicit, so the end of step velocities are
+/// estimated with the Newtonian kick and then refined `iterations` times before the final kick.
+#[derive(Copy, Clone, Debug)]
+pub struct PostNewtonian {
+    /// Speed of light in simulation units
+    pub c: f32,
+    pub iterations: u32,
+}
+
+impl PostNewtonian {
+    pub fn new(c: f32) -> Self {
+        PostNewtonian { c, iterations: 2 }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct PostNewtonianParams {
+    inv_c2: f32,
+    _padding: [u32; 3],
+}
+
+/// Size of `PostNewtonianState` in `post_newtonian.wgsl`.
+const STATE_SIZE: usize = std::mem::size_of::<[[f32; 4]; 3]>();
+
+/// Runs the 1PN entry points of the naive kernel in place of its main pipeline.
+pub(crate) struct PostNewtonianStepper {
+    config: PostNewtonian,
+    params_buffer: wgpu::Buffer,
+    state_buffer: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    drift_pipeline: wgpu::ComputePipeline,
+    newton_pipeline: wgpu::ComputePipeline,
+    iterate_pipelines: [wgpu::ComputePipeline; 2],
+    kick_pipelines: [wgpu::ComputePipeline; 2],
+}
+
+impl PostNewtonianStepper {
+    /// `kernel_bind_group_layouts` are the naive kernel's own layouts (groups 0 and 1), the
+    /// post-Newtonian bindings follow them. Fails unless `c` is positive.
+    pub fn new(
+        device: &wgpu::Device,
+        config: PostNewtonian,
+        kernel_bind_group_layouts: &[&wgpu::BindGroupLayout],
+        particle_num: u32,
+    ) -> Result<Self> {
+        ensure!(config.c > 0.0, "the speed of light must be positive");
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Post-Newtonian Params Buffer"),
+            contents: bytemuck::cast_slice(&[PostNewtonianParams {
+                inv_c2: 1.0 / (config.c * config.c),
+                _padding: [0; 3],
+            }]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let state_buffer = Self::create_state_buffer(device, particle_num);
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Post-Newtonian Bind Group Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(std::mem::size_of::<
+                            PostNewtonianParams,
+                        >() as _),
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let bind_group =
+            Self::create_bind_group(device, &bind_group_layout, &params_buffer, &state_buffer);
+
+        let compute_module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Post-Newtonian Module"),
+            source: wgpu::ShaderSource::Wgsl(
+                ForceLaw::Newtonian
+                    .kernel_source(
+                        include_str!("shaders/naive.wgsl"),
+                        include_str!("shaders/post_newtonian.wgsl"),
+                    )
+                    .into(),
+            ),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Post-Newtonian Pipeline Layout"),
+            bind_group_layouts: &[kernel_bind_group_layouts, &[&bind_group_layout]].concat(),
+            push_constant_ranges: &[],
+        });
+        let create_pipeline = |entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: &compute_module,
+                entry_point,
+            })
+        };
+
+        Ok(Self {
+            config,
+            params_buffer,
+            state_buffer,
+            bind_group_layout,
+            bind_group,
+            drift_pipeline: create_pipeline("pn_drift"),
+            newton_pipeline: create_pipeline("pn_newton"),
+            iterate_pipelines: ["pn_iterate0", "pn_iterate1"].map(create_pipeline),
+            kick_pipelines: ["pn_kick0", "pn_kick1"].map(create_pipeline),
+        })
+    }
+
+    /// Makes room for the state of `particle_num` particles.
+    pub fn resize(&mut self, device: &wgpu::Device, particle_num: u32) {
+        self.state_buffer = Self::create_state_buffer(device, particle_num);
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.params_buffer,
+            &self.state_buffer,
+        );
+    }
+
+    /// Records one step. `kernel_bind_groups` are the naive kernel's bind groups, the first one
+    /// reading the previous state and writing the next.
+    pub fn encode(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        kernel_bind_groups: &[&wgpu::BindGroup],
+        work_group_count: u32,
+    ) {
+        encoder.push_debug_group("post-Newtonian step");
+        {
+            let mut cpass =
+                encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+            for (i, bind_group) in kernel_bind_groups.iter().enumerate() {
+                cpass.set_bind_group(i as u32, bind_group, &[]);
+            }
+            cpass.set_bind_group(kernel_bind_groups.len() as u32, &self.bind_group, &[]);
+            cpass.set_pipeline(&self.drift_pipeline);
+            cpass.dispatch(work_group_count, 1, 1);
+            cpass.set_pipeline(&self.newton_pipeline);
+            cpass.dispatch(work_group_count, 1, 1);
+            // each iteration reads one velocity estimate and writes the other
+            for i in 0..self.config.iterations as usize {
+                cpass.set_pipeline(&self.iterate_pipelines[i % 2]);
+                cpass.dispatch(work_group_count, 1, 1);
+            }
+            cpass.set_pipeline(&self.kick_pipelines[self.config.iterations as usize % 2]);
+            cpass.dispatch(work_group_count, 1, 1);
+        }
+        encoder.pop_debug_group();
+    }
+
+    fn create_state_buffer(device: &wgpu::Device, particle_num: u32) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Post-Newtonian State Buffer"),
+            size: (STATE_SIZE * particle_num.max(1) as usize) as _,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        params_buffer: &wgpu::Buffer,
+        state_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Post-Newtonian Bind Group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: state_buffer.as_entire_binding(),
+                },
+            ],
+        })
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::{ensure, Result};
+
+/// Most potentials that can be active at once.
+pub const MAX_EXTERNAL_POTENTIALS: usize = 8;
+
+/// Fixed analytic background potential added to the self-gravity of every particle. Masses are
+/// scaled by `SimParams::g` like particle masses.
+#[derive(Copy, Clone, Debug)]
+pub enum ExternalPotential {
+    PointMass {
+        center: [f32; 3],
+        mass: f32,
+    },
+    Plummer {
+        center: [f32; 3],
+        mass: f32,
+        scale_radius: f32,
+    },
+    Hernquist {
+        center: [f32; 3],
+        mass: f32,
+        scale_radius: f32,
+    },
+    /// `mass` is the characteristic mass `4 pi rho_0 r_s^3`, not the (divergent) total mass
+    Nfw {
+        center: [f32; 3],
+        mass: f32,
+        scale_radius: f32,
+    },
+    /// Axisymmetric disc in the xy plane, `scale_height` must be positive
+    MiyamotoNagai {
+        center: [f32; 3],
+        mass: f32,
+        scale_length: f32,
+        scale_height: f32,
+    },
+    /// `0.5 * v0^2 * ln(core_radius^2 + x^2 + y^2 + (z / q)^2)`, independent of `g`
+    Logarithmic {
+        center: [f32; 3],
+        v0: f32,
+        core_radius: f32,
+        q: f32,
+    },
+    /// Constant acceleration everywhere, independent of `g`
+    UniformField {
+        acceleration: [f32; 3],
+    },
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct PotentialUniform {
+    kind: u32,
+    _padding: [u32; 3],
+    center: [f32; 4],
+    args: [f32; 4],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct PotentialsUniform {
+    count: u32,
+    _padding: [u32; 3],
+    potentials: [PotentialUniform; MAX_EXTERNAL_POTENTIALS],
+}
+
+impl ExternalPotential {
+    /// Layout matching `ExternalPotential` in `potential.wgsl`.
+    fn to_uniform(self) -> PotentialUniform {
+        let (kind, center, args) = match self {
+            ExternalPotential::PointMass { center, mass } => (1, center, [mass, 0.0, 0.0]),
+            ExternalPotential::Plummer {
+                center,
+                mass,
+                scale_radius,
+            } => (2, center, [mass, scale_radius, 0.0]),
+            ExternalPotential::Hernquist {
+                center,
+                mass,
+                scale_radius,
+            } => (3, center, [mass, scale_radius, 0.0]),
+            ExternalPotential::Nfw {
+                center,
+                mass,
+                scale_radius,
+            } => (4, center, [mass, scale_radius, 0.0]),
+            ExternalPotential::MiyamotoNagai {
+                center,
+                mass,
+                scale_length,
+                scale_height,
+            } => (5, center, [mass, scale_length, scale_height]),
+            ExternalPotential::Logarithmic {
+                center,
+                v0,
+                core_radius,
+                q,
+            } => (6, center, [v0, core_radius, q]),
+            ExternalPotential::UniformField { acceleration } => (7, [0.0; 3], acceleration),
+        };
+        PotentialUniform {
+            kind,
+            _padding: [0; 3],
+            center: [center[0], center[1], center[2], 0.0],
+            args: [args[0], args[1], args[2], 0.0],
+        }
+    }
+}
+
+impl PotentialsUniform {
+    /// Layout matching `ExternalPotentials` in `potential.wgsl`. Fails if more than
+    /// `MAX_EXTERNAL_POTENTIALS` are given.
+    pub fn new(potentials: &[ExternalPotential]) -> Result<Self> {
+        ensure!(
+            potentials.len() <= MAX_EXTERNAL_POTENTIALS,
+            "at most {} external potentials are supported",
+            MAX_EXTERNAL_POTENTIALS
+        );
+        let mut uniform = PotentialsUniform {
+            count: potentials.len() as u32,
+            ..Default::default()
+        };
+        for (dst, potential) in uniform.potentials.iter_mut().zip(potentials) {
+            *dst = potential.to_uniform();
+        }
+        Ok(uniform)
+    }
+}
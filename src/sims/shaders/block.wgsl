@@ -0,0 +1,115 @@
+// Block (individual) timestep entry points. Appended to a force kernel, which provides
+// `params`, `particlesSrc`, `particlesDst` and `getAccPot`.
+
+struct BlockParams {
+    // substep within the current block cycle, 0 .. 2^max_level
+    substep: u32;
+    max_level: u32;
+    eta: f32;
+    softening: f32;
+    // 1 on the first substep after enabling, where there is no previous step to close
+    first: u32;
+    _padding0: u32;
+    _padding1: u32;
+    _padding2: u32;
+};
+
+struct ActiveList {
+    count: atomic<u32>;
+    indices: array<u32>;
+};
+
+struct DispatchArgs {
+    x: u32;
+    y: u32;
+    z: u32;
+};
+
+[[group(2), binding(0)]] var<uniform> block_params: BlockParams;
+[[group(2), binding(1)]] var<storage, read_write> active: ActiveList;
+[[group(2), binding(2)]] var<storage, read_write> dispatch_args: DispatchArgs;
+
+// substeps between kicks of a particle on `level`
+fn levelPeriod(level: u32) -> u32 {
+    return 1u << (block_params.max_level - level);
+}
+
+fn levelDt(level: u32) -> f32 {
+    return params.dt / f32(1u << level);
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn block_select([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
+    let index = global_invocation_id.x;
+    if (index >= params.num_particles) {
+        return;
+    }
+    let level = particlesSrc.particles[index].level;
+    if (block_params.first == 1u || block_params.substep % levelPeriod(level) == 0u) {
+        let slot = atomicAdd(&active.count, 1u);
+        active.indices[slot] = index;
+    }
+}
+
+[[stage(compute), workgroup_size(1)]]
+fn block_args() {
+    let count = atomicLoad(&active.count);
+    dispatch_args.x = (count + 63u) / 64u;
+    dispatch_args.y = 1u;
+    dispatch_args.z = 1u;
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn block_kick([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
+    if (global_invocation_id.x >= atomicLoad(&active.count)) {
+        return;
+    }
+    let index = active.indices[global_invocation_id.x];
+    let _p = particlesSrc.particles[index];
+    let aPos = vec3<f32>(_p.px, _p.py, _p.pz);
+    var aVel = vec3<f32>(_p.vx, _p.vy, _p.vz);
+    let acc_pot = getAccPot(aPos, index);
+    let acc = acc_pot.xyz;
+
+    // finest level needed to resolve the new acceleration
+    var level: u32 = 0u;
+    let acc_mag = length(acc);
+    if (acc_mag > 0.0) {
+        let dt_crit = block_params.eta * sqrt(block_params.softening / acc_mag);
+        loop {
+            if (level >= block_params.max_level || levelDt(level) <= dt_crit) {
+                break;
+            }
+            level = level + 1u;
+        }
+    }
+    // a coarser level can only be entered on one of its own boundaries
+    loop {
+        if (block_params.substep % levelPeriod(level) == 0u) {
+            break;
+        }
+        level = level + 1u;
+    }
+
+    // closing half-kick of the previous step merged with the opening half-kick of the next
+    var dt_prev = levelDt(_p.level);
+    if (block_params.first == 1u) {
+        dt_prev = 0.0;
+    }
+    aVel = aVel + acc * (dt_prev + levelDt(level)) / 2.0;
+
+    particlesDst.particles[index] = Particle(_p.px, _p.py, _p.pz, aVel.x, aVel.y, aVel.z, acc.x, acc.y, acc.z, _p.mass, _p.id, level, _p.radius, _p.species, _p.charge, acc_pot.w);
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn block_drift([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
+    let index = global_invocation_id.x;
+    if (index >= params.num_particles) {
+        return;
+    }
+    let h = levelDt(block_params.max_level);
+    let _p = particlesDst.particles[index];
+    particlesDst.particles[index].px = _p.px + _p.vx * h;
+    particlesDst.particles[index].py = _p.py + _p.vy * h;
+    particlesDst.particles[index].pz = _p.pz + _p.vz * h;
+}
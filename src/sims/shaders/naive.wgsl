@@ -1,30 +1,26 @@
-struct Particle {
-    px: f32; py: f32; pz: f32;
-    vx: f32; vy: f32; vz: f32;
-    ax: f32; ay: f32; az: f32;
-    mass: f32;
-};
-
-struct SimParams {
-    num_particles: u32;
-    g: f32;
-    e: f32;
-    dt: f32;
-};
-
-struct Particles {
-    particles: [[stride(40)]] array<Particle>;
+struct NaiveParams {
+    // massive particles come first, the rest are tracers that don't source gravity
+    massive_num: u32;
+    _padding0: u32;
+    _padding1: u32;
+    _padding2: u32;
 };
 
 [[group(0), binding(0)]] var<uniform> params: SimParams;
 [[group(0), binding(1)]] var<storage, read> particlesSrc: Particles;
 [[group(0), binding(2)]] var<storage, read_write> particlesDst: Particles;
+[[group(0), binding(3)]] var<uniform> naive_params: NaiveParams;
 
-fn getAcc(aPos: vec3<f32>, index: u32, total: u32) -> vec3<f32> {
+// xyz: acceleration, w: potential of the massive particles (external potentials excluded)
+fn getAccPot(aPos: vec3<f32>, index: u32) -> vec4<f32> {
     var acc = vec3<f32>(0.0, 0.0, 0.0);
+    var pot: f32 = 0.0;
+    let _p = particlesSrc.particles[index];
+    let e = speciesSoftening(_p.species, params.e);
+    let sink_qm = select(0.0, _p.charge / _p.mass, _p.mass != 0.0);
     var i: u32 = 0u;
     loop {
-        if (i >= total) {
+        if (i >= naive_params.massive_num) {
             break;
         }
         if (i == index) {
@@ -36,20 +32,19 @@ fn getAcc(aPos: vec3<f32>, index: u32, total: u32) -> vec3<f32> {
         var bVel = vec3<f32>(_q.vx, _q.vy, _q.vz);
 
         let r: f32 = distance(aPos, bPos);
-        let force: vec3<f32> = _q.mass * params.g / (r * r * r + params.e) * normalize(bPos - aPos); 
-        let _acc: vec3<f32> = force;
-        acc = acc + _acc * params.dt;
+        acc = acc + pairAcc(bPos - aPos, r, params.g, e, _q.mass, _q.charge, sink_qm);
+        pot = pot + pairPot(r, params.g, e, _q.mass, _q.charge, sink_qm);
 
         continuing {
             i = i + 1u;
         }
     }
-    return acc;
+    return vec4<f32>(acc + externalAcc(aPos, params.g), pot);
 }
 
 [[stage(compute), workgroup_size(64)]]
 fn main([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
-    let total = arrayLength(&particlesSrc.particles);
+    let total = params.num_particles;
     let index = global_invocation_id.x;
     if (index >= total) {
         return;
@@ -62,8 +57,9 @@ fn main([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
 
     aVel = aVel + aAcc * params.dt / 2.0;
     aPos = aPos + aVel * params.dt;
-    let acc = getAcc(aPos, index, total);
+    let acc_pot = getAccPot(aPos, index);
+    let acc = acc_pot.xyz;
     aVel = aVel + acc * params.dt / 2.0;
 
-    particlesDst.particles[index] = Particle(aPos.x, aPos.y, aPos.z, aVel.x, aVel.y, aVel.z, acc.x, acc.y, acc.z, _p.mass);
+    particlesDst.particles[index] = Particle(aPos.x, aPos.y, aPos.z, aVel.x, aVel.y, aVel.z, acc.x, acc.y, acc.z, _p.mass, _p.id, _p.level, _p.radius, _p.species, _p.charge, acc_pot.w);
 }
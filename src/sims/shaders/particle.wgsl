@@ -0,0 +1,25 @@
+// Shared by every particle kernel, prepended to the kernel sources at build time.
+
+struct Particle {
+    px: f32; py: f32; pz: f32;
+    vx: f32; vy: f32; vz: f32;
+    ax: f32; ay: f32; az: f32;
+    mass: f32;
+    id: u32;
+    level: u32;
+    radius: f32;
+    species: u32;
+    charge: f32;
+    potential: f32;
+};
+
+struct SimParams {
+    num_particles: u32;
+    g: f32;
+    e: f32;
+    dt: f32;
+};
+
+struct Particles {
+    particles: [[stride(64)]] array<Particle>;
+};
@@ -0,0 +1,192 @@
+// First post-Newtonian (Einstein-Infeld-Hoffmann) entry points. Appended to the naive kernel,
+// which provides `params`, `particlesSrc`, `particlesDst` and `naive_params`. A step drifts every
+// particle, evaluates the Newtonian field at the new positions and then iterates the end of step
+// velocities the velocity dependent 1PN terms are evaluated with before the final kick.
+
+struct PostNewtonianParams {
+    // 1 / c^2
+    inv_c2: f32;
+    _padding0: u32;
+    _padding1: u32;
+    _padding2: u32;
+};
+
+struct PostNewtonianState {
+    // xyz: Newtonian acceleration, w: sum of g * m / r over all sources
+    newton: vec4<f32>;
+    // end of step velocity estimates, iterations alternate between the two
+    vel0: vec4<f32>;
+    vel1: vec4<f32>;
+};
+
+struct PostNewtonianStates {
+    states: [[stride(48)]] array<PostNewtonianState>;
+};
+
+[[group(2), binding(0)]] var<uniform> pn_params: PostNewtonianParams;
+[[group(2), binding(1)]] var<storage, read_write> pn: PostNewtonianStates;
+
+fn pnPos(index: u32) -> vec3<f32> {
+    let _p = particlesDst.particles[index];
+    return vec3<f32>(_p.px, _p.py, _p.pz);
+}
+
+// half kicked velocity the particle drifted with
+fn pnHalfVel(index: u32) -> vec3<f32> {
+    let _p = particlesDst.particles[index];
+    return vec3<f32>(_p.vx, _p.vy, _p.vz);
+}
+
+fn pnVel(index: u32, estimate: u32) -> vec3<f32> {
+    if (estimate == 0u) {
+        return pn.states[index].vel0.xyz;
+    }
+    return pn.states[index].vel1.xyz;
+}
+
+// Newtonian plus 1PN acceleration of `index` using the `estimate` velocities
+fn pnAcc(index: u32, estimate: u32) -> vec3<f32> {
+    let aPos = pnPos(index);
+    let aVel = pnVel(index, estimate);
+    let aPot = pn.states[index].newton.w;
+    let e = speciesSoftening(particlesDst.particles[index].species, params.e);
+    let inv_c2 = pn_params.inv_c2;
+    var acc = vec3<f32>(0.0, 0.0, 0.0);
+    var i: u32 = 0u;
+    loop {
+        if (i >= naive_params.massive_num) {
+            break;
+        }
+        if (i == index) {
+            continue;
+        }
+
+        let bPos = pnPos(i);
+        let bVel = pnVel(i, estimate);
+        let bState = pn.states[i];
+        let d = bPos - aPos;
+        let r = length(d);
+        // softened so that g * m / rs^2 matches the Newtonian magnitude
+        let rs = pow(r * r * r + e, 1.0 / 3.0);
+        let gm = params.g * particlesDst.particles[i].mass;
+        // unit vector from the source to the sink
+        let n = -d / r;
+
+        let bracket = 1.0
+            - inv_c2 * (4.0 * aPot + bState.newton.w)
+            + inv_c2 * (dot(aVel, aVel) + 2.0 * dot(bVel, bVel) - 4.0 * dot(aVel, bVel))
+            - inv_c2 * 1.5 * dot(n, bVel) * dot(n, bVel)
+            + inv_c2 * 0.5 * dot(d, bState.newton.xyz);
+        acc = acc + gm * d / (r * r * r + e) * bracket;
+        acc = acc + inv_c2 * gm / (rs * rs) * dot(n, 4.0 * aVel - 3.0 * bVel) * (aVel - bVel);
+        acc = acc + inv_c2 * 3.5 * gm / rs * bState.newton.xyz;
+
+        continuing {
+            i = i + 1u;
+        }
+    }
+    return acc + externalAcc(aPos, params.g);
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn pn_drift([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
+    let index = global_invocation_id.x;
+    if (index >= params.num_particles) {
+        return;
+    }
+    let _p = particlesSrc.particles[index];
+    var aPos = vec3<f32>(_p.px, _p.py, _p.pz);
+    var aVel = vec3<f32>(_p.vx, _p.vy, _p.vz);
+    let aAcc = vec3<f32>(_p.ax, _p.ay, _p.az);
+
+    aVel = aVel + aAcc * params.dt / 2.0;
+    aPos = aPos + aVel * params.dt;
+
+    particlesDst.particles[index] = Particle(aPos.x, aPos.y, aPos.z, aVel.x, aVel.y, aVel.z, aAcc.x, aAcc.y, aAcc.z, _p.mass, _p.id, _p.level, _p.radius, _p.species, _p.charge, _p.potential);
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn pn_newton([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
+    let index = global_invocation_id.x;
+    if (index >= params.num_particles) {
+        return;
+    }
+    let aPos = pnPos(index);
+    let e = speciesSoftening(particlesDst.particles[index].species, params.e);
+    var acc = vec3<f32>(0.0, 0.0, 0.0);
+    var pot: f32 = 0.0;
+    var i: u32 = 0u;
+    loop {
+        if (i >= naive_params.massive_num) {
+            break;
+        }
+        if (i == index) {
+            continue;
+        }
+
+        let d = pnPos(i) - aPos;
+        let r = length(d);
+        let gm = params.g * particlesDst.particles[i].mass;
+        acc = acc + gm * d / (r * r * r + e);
+        pot = pot + gm / pow(r * r * r + e, 1.0 / 3.0);
+
+        continuing {
+            i = i + 1u;
+        }
+    }
+    pn.states[index].newton = vec4<f32>(acc, pot);
+    particlesDst.particles[index].potential = -pot;
+    // the Newtonian kick is the first estimate of the end of step velocity
+    let vel = pnHalfVel(index) + (acc + externalAcc(aPos, params.g)) * params.dt / 2.0;
+    pn.states[index].vel0 = vec4<f32>(vel, 0.0);
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn pn_iterate0([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
+    let index = global_invocation_id.x;
+    if (index >= params.num_particles) {
+        return;
+    }
+    let vel = pnHalfVel(index) + pnAcc(index, 0u) * params.dt / 2.0;
+    pn.states[index].vel1 = vec4<f32>(vel, 0.0);
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn pn_iterate1([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
+    let index = global_invocation_id.x;
+    if (index >= params.num_particles) {
+        return;
+    }
+    let vel = pnHalfVel(index) + pnAcc(index, 1u) * params.dt / 2.0;
+    pn.states[index].vel0 = vec4<f32>(vel, 0.0);
+}
+
+// only writes the particle's own velocity and acceleration, which no other invocation reads
+fn pnKick(index: u32, estimate: u32) {
+    let acc = pnAcc(index, estimate);
+    let aVel = pnHalfVel(index) + acc * params.dt / 2.0;
+    particlesDst.particles[index].vx = aVel.x;
+    particlesDst.particles[index].vy = aVel.y;
+    particlesDst.particles[index].vz = aVel.z;
+    particlesDst.particles[index].ax = acc.x;
+    particlesDst.particles[index].ay = acc.y;
+    particlesDst.particles[index].az = acc.z;
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn pn_kick0([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
+    let index = global_invocation_id.x;
+    if (index >= params.num_particles) {
+        return;
+    }
+    pnKick(index, 0u);
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn pn_kick1([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
+    let index = global_invocation_id.x;
+    if (index >= params.num_particles) {
+        return;
+    }
+    pnKick(index, 1u);
+}
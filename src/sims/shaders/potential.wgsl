@@ -0,0 +1,99 @@
+// Analytic external potentials, prepended to the force kernels after particle.wgsl.
+
+struct ExternalPotential {
+    // 0: none, 1: point mass, 2: Plummer, 3: Hernquist, 4: NFW, 5: Miyamoto-Nagai,
+    // 6: logarithmic, 7: uniform field
+    kind: u32;
+    _padding0: u32;
+    _padding1: u32;
+    _padding2: u32;
+    // xyz: center of the potential
+    center: vec4<f32>;
+    // meaning depends on kind, see ExternalPotential in potential.rs
+    args: vec4<f32>;
+};
+
+struct ExternalPotentials {
+    count: u32;
+    _padding0: u32;
+    _padding1: u32;
+    _padding2: u32;
+    potentials: array<ExternalPotential, 8>;
+};
+
+[[group(1), binding(0)]] var<uniform> external: ExternalPotentials;
+
+fn potentialAcc(pot: ExternalPotential, pos: vec3<f32>, g: f32) -> vec3<f32> {
+    let d = pos - pot.center.xyz;
+    let r = length(d);
+    switch (pot.kind) {
+        case 1u: {
+            // args.x: mass
+            if (r == 0.0) {
+                return vec3<f32>(0.0, 0.0, 0.0);
+            }
+            return -g * pot.args.x / (r * r * r) * d;
+        }
+        case 2u: {
+            // args.x: mass, args.y: scale radius
+            let s = r * r + pot.args.y * pot.args.y;
+            return -g * pot.args.x / (s * sqrt(s)) * d;
+        }
+        case 3u: {
+            // args.x: mass, args.y: scale radius
+            if (r == 0.0) {
+                return vec3<f32>(0.0, 0.0, 0.0);
+            }
+            let s = r + pot.args.y;
+            return -g * pot.args.x / (r * s * s) * d;
+        }
+        case 4u: {
+            // args.x: characteristic mass 4 pi rho_0 r_s^3, args.y: scale radius
+            if (r == 0.0) {
+                return vec3<f32>(0.0, 0.0, 0.0);
+            }
+            let x = r / pot.args.y;
+            let enclosed = log(1.0 + x) - x / (1.0 + x);
+            return -g * pot.args.x * enclosed / (r * r * r) * d;
+        }
+        case 5u: {
+            // args.x: mass, args.y: disc scale length a, args.z: disc scale height b
+            let zeta = sqrt(d.z * d.z + pot.args.z * pot.args.z);
+            let az = pot.args.y + zeta;
+            let s = d.x * d.x + d.y * d.y + az * az;
+            let k = -g * pot.args.x / (s * sqrt(s));
+            if (zeta == 0.0) {
+                return vec3<f32>(k * d.x, k * d.y, 0.0);
+            }
+            return vec3<f32>(k * d.x, k * d.y, k * d.z * az / zeta);
+        }
+        case 6u: {
+            // args.x: circular velocity v0, args.y: core radius, args.z: flattening q
+            let q2 = pot.args.z * pot.args.z;
+            let s = pot.args.y * pot.args.y + d.x * d.x + d.y * d.y + d.z * d.z / q2;
+            let k = -pot.args.x * pot.args.x / s;
+            return vec3<f32>(k * d.x, k * d.y, k * d.z / q2);
+        }
+        case 7u: {
+            // args.xyz: acceleration
+            return pot.args.xyz;
+        }
+        default: {
+            return vec3<f32>(0.0, 0.0, 0.0);
+        }
+    }
+}
+
+// total acceleration from all external potentials at `pos`
+fn externalAcc(pos: vec3<f32>, g: f32) -> vec3<f32> {
+    var acc = vec3<f32>(0.0, 0.0, 0.0);
+    var i: u32 = 0u;
+    loop {
+        if (i >= external.count) {
+            break;
+        }
+        acc = acc + potentialAcc(external.potentials[i], pos, g);
+        i = i + 1u;
+    }
+    return acc;
+}
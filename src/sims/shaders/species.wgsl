@@ -0,0 +1,17 @@
+// Per-species table, prepended to the force kernels after particle.wgsl.
+
+struct SpeciesTable {
+    // x: softening, negative to use SimParams.e
+    softening: array<vec4<f32>, 8>;
+};
+
+[[group(1), binding(1)]] var<uniform> species_table: SpeciesTable;
+
+// softening for forces felt by a particle of `species`
+fn speciesSoftening(species: u32, default_softening: f32) -> f32 {
+    let softening = species_table.softening[min(species, 7u)].x;
+    if (softening < 0.0) {
+        return default_softening;
+    }
+    return softening;
+}
@@ -0,0 +1,84 @@
+struct TimestepParams {
+    eta: f32;
+    courant: f32;
+    softening: f32;
+    _padding: f32;
+};
+
+struct Timesteps {
+    values: array<f32>;
+};
+
+[[group(0), binding(0)]] var<uniform> params: SimParams;
+[[group(0), binding(1)]] var<uniform> ts_params: TimestepParams;
+[[group(0), binding(2)]] var<storage, read> particlesSrc: Particles;
+[[group(0), binding(3)]] var<storage, read_write> partials: Timesteps;
+[[group(0), binding(4)]] var<storage, read_write> result: Timesteps;
+
+let NO_LIMIT: f32 = 3.0e38;
+
+var<workgroup> shared_min: array<f32, 64>;
+
+// leaves the minimum of shared_min in its first slot
+fn reduceShared(local_ix: u32) {
+    var stride: u32 = 32u;
+    loop {
+        if (stride == 0u) {
+            break;
+        }
+        if (local_ix < stride) {
+            shared_min[local_ix] = min(shared_min[local_ix], shared_min[local_ix + stride]);
+        }
+        workgroupBarrier();
+        stride = stride / 2u;
+    }
+}
+
+// first pass: per-particle timestep criteria, reduced to one value per workgroup
+[[stage(compute), workgroup_size(64)]]
+fn reduce_particles(
+    [[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>,
+    [[builtin(local_invocation_id)]] local_invocation_id: vec3<u32>,
+    [[builtin(workgroup_id)]] workgroup_id: vec3<u32>,
+) {
+    let index = global_invocation_id.x;
+    var dt = NO_LIMIT;
+    if (index < params.num_particles) {
+        let _p = particlesSrc.particles[index];
+        let acc = length(vec3<f32>(_p.ax, _p.ay, _p.az));
+        let vel = length(vec3<f32>(_p.vx, _p.vy, _p.vz));
+        if (acc > 0.0) {
+            dt = min(dt, ts_params.eta * sqrt(ts_params.softening / acc));
+        }
+        if (vel > 0.0) {
+            dt = min(dt, ts_params.courant * ts_params.softening / vel);
+        }
+    }
+    shared_min[local_invocation_id.x] = dt;
+    workgroupBarrier();
+    reduceShared(local_invocation_id.x);
+    if (local_invocation_id.x == 0u) {
+        partials.values[workgroup_id.x] = shared_min[0];
+    }
+}
+
+// second pass: a single workgroup reduces the per-workgroup minimums
+[[stage(compute), workgroup_size(64)]]
+fn reduce_partials([[builtin(local_invocation_id)]] local_invocation_id: vec3<u32>) {
+    let total = arrayLength(&partials.values);
+    var dt = NO_LIMIT;
+    var i: u32 = local_invocation_id.x;
+    loop {
+        if (i >= total) {
+            break;
+        }
+        dt = min(dt, partials.values[i]);
+        i = i + 64u;
+    }
+    shared_min[local_invocation_id.x] = dt;
+    workgroupBarrier();
+    reduceShared(local_invocation_id.x);
+    if (local_invocation_id.x == 0u) {
+        result.values[0] = shared_min[0];
+    }
+}
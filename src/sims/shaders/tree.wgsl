@@ -1,35 +1,24 @@
 struct Octant {
     cx: f32; cy: f32; cz: f32;
     mass: f32;
+    charge: f32;
     bodies: u32;
-    children: array<u32,8>;
+    // children are stored back to back from first_child, leaves hold a particle index instead
+    first_child: u32;
+    // bits 0-7: occupied children, DEGENERATE: children keep this node's width
+    child_mask: u32;
 };
 
-struct Particle {
-    px: f32; py: f32; pz: f32;
-    vx: f32; vy: f32; vz: f32;
-    ax: f32; ay: f32; az: f32;
-    mass: f32;
-};
-
-struct SimParams {
-    num_particles: u32;
-    g: f32;
-    e: f32;
-    dt: f32;
-};
+// set on nodes too small to split by position, whose bodies are spread over the children
+let DEGENERATE: u32 = 256u;
 
 struct TreeSimParams {
     theta: f32;
     root_width: f32;
 };
 
-struct Particles {
-    particles: [[stride(40)]] array<Particle>;
-};
-
 struct Octants {
-    octants: [[stride(52)]] array<Octant>;
+    octants: [[stride(32)]] array<Octant>;
 };
 
 [[group(0), binding(0)]] var<uniform> params: SimParams;
@@ -38,8 +27,13 @@ struct Octants {
 [[group(0), binding(3)]] var<storage, read> treeSrc: Octants;
 [[group(0), binding(4)]] var<storage, read_write> particlesDst: Particles;
 
-fn getAcc(aPos: vec3<f32>, index: u32, total: u32) -> vec3<f32> {
+// xyz: acceleration, w: potential of the massive particles (external potentials excluded)
+fn getAccPot(aPos: vec3<f32>, index: u32) -> vec4<f32> {
     var acc = vec3<f32>(0.0, 0.0, 0.0);
+    var pot: f32 = 0.0;
+    let _p = particlesSrc.particles[index];
+    let e = speciesSoftening(_p.species, params.e);
+    let sink_qm = select(0.0, _p.charge / _p.mass, _p.mass != 0.0);
     // simulated recursive stack (quad index stack, node width stack)
     var oct_stack: array<u32, 64>;
     var size_stack: array<f32, 64>;
@@ -55,43 +49,42 @@ fn getAcc(aPos: vec3<f32>, index: u32, total: u32) -> vec3<f32> {
         let top_size = size_stack[size - 1u];
         let cog = vec3<f32>(top_oct.cx, top_oct.cy, top_oct.cz);
         let dist = distance(aPos, cog);
-        if ( top_oct.bodies == 1u && dist < 0.000001 ) {
-            // same body so skip calculation
+        if ( top_oct.mass == 0.0 || (top_oct.bodies == 1u && dist < 0.000001) ) {
+            // empty tree (only tracers) or same body so skip calculation
             size = size - 1u;
             continue;
         }
         let sd = top_size / dist;
-        if (sd < tree_params.theta) {
+        // leaves hold a particle index instead of children so they are always a single body
+        if (sd < tree_params.theta || top_oct.bodies == 1u) {
             // treat this as a single body since it's sufficiently far away
-            let force: vec3<f32> = top_oct.mass * params.g / (dist * dist * dist + params.e) * normalize(cog - aPos);
-            acc = acc + force * params.dt;
+            acc = acc + pairAcc(cog - aPos, dist, params.g, e, top_oct.mass, top_oct.charge, sink_qm);
+            pot = pot + pairPot(dist, params.g, e, top_oct.mass, top_oct.charge, sink_qm);
             size = size - 1u;
             continue;
         }
         // otherwise recurse further (after removing current frame)
         size = size - 1u;
-        var curr_ix = oct_stack[size];
+        let child_count = countOneBits(top_oct.child_mask & 255u);
+        let child_size = select(top_size / 2.0, top_size, (top_oct.child_mask & DEGENERATE) != 0u);
         var i: u32 = 0u;
         loop {
-            if (i >= 8u) {
+            if (i >= child_count) {
                 break;
             }
-            // add each subsection to the stack if it exists
-            let child_ix = treeSrc.octants[curr_ix].children[i];
-            if (child_ix != 0u) {
-                size_stack[size] = top_size / 2.0;
-                oct_stack[size] = child_ix;
-                size = size + 1u;
-            }
+            // add each occupied subsection to the stack
+            size_stack[size] = child_size;
+            oct_stack[size] = top_oct.first_child + i;
+            size = size + 1u;
             i = i + 1u;
         }
     }
-    return acc;
+    return vec4<f32>(acc + externalAcc(aPos, params.g), pot);
 }
 
 [[stage(compute), workgroup_size(64)]]
 fn main([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
-    let total = arrayLength(&particlesSrc.particles);
+    let total = params.num_particles;
     let index = global_invocation_id.x;
     if (index >= total) {
         return;
@@ -104,8 +97,9 @@ fn main([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
 
     aVel = aVel + aAcc * params.dt / 2.0;
     aPos = aPos + aVel * params.dt;
-    let acc = getAcc(aPos, index, total);
+    let acc_pot = getAccPot(aPos, index);
+    let acc = acc_pot.xyz;
     aVel = aVel + acc * params.dt / 2.0;
 
-    particlesDst.particles[index] = Particle(aPos.x, aPos.y, aPos.z, aVel.x, aVel.y, aVel.z, acc.x, acc.y, acc.z, _p.mass);
+    particlesDst.particles[index] = Particle(aPos.x, aPos.y, aPos.z, aVel.x, aVel.y, aVel.z, acc.x, acc.y, acc.z, _p.mass, _p.id, _p.level, _p.radius, _p.species, _p.charge, acc_pot.w);
 }
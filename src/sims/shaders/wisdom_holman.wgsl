@@ -0,0 +1,353 @@
+// Wisdom-Holman entry points in democratic heliocentric coordinates. Appended to the naive
+// kernel, which provides `params`, `particlesSrc`, `particlesDst` and `naive_params`. Bodies drift
+// on Kepler orbits around the central body and are kicked by every other massive body, the
+// central body follows from the barycentre. Positions and velocities are converted from and back
+// to the inertial frame every step, the serial passes run on a single invocation since these
+// systems only have few bodies.
+
+struct WisdomHolmanParams {
+    central_id: u32;
+    // 1 to look up `central_id`, 0 to use the most massive body
+    use_id: u32;
+    _padding0: u32;
+    _padding1: u32;
+};
+
+struct WisdomHolmanState {
+    central: u32;
+    _padding0: u32;
+    _padding1: u32;
+    _padding2: u32;
+    // xyz: barycentre position, w: total mass
+    bary_pos: vec4<f32>;
+    // xyz: barycentre velocity, w: central mass
+    bary_vel: vec4<f32>;
+    // sum of the barycentric momenta of every body but the central one
+    momentum: vec4<f32>;
+};
+
+struct KeplerState {
+    pos: vec3<f32>;
+    vel: vec3<f32>;
+};
+
+[[group(2), binding(0)]] var<uniform> wh_params: WisdomHolmanParams;
+[[group(2), binding(1)]] var<storage, read_write> wh: WisdomHolmanState;
+
+fn whSrcPos(index: u32) -> vec3<f32> {
+    let _p = particlesSrc.particles[index];
+    return vec3<f32>(_p.px, _p.py, _p.pz);
+}
+
+fn whDstPos(index: u32) -> vec3<f32> {
+    let _p = particlesDst.particles[index];
+    return vec3<f32>(_p.px, _p.py, _p.pz);
+}
+
+fn whDstVel(index: u32) -> vec3<f32> {
+    let _p = particlesDst.particles[index];
+    return vec3<f32>(_p.vx, _p.vy, _p.vz);
+}
+
+// acceleration from every massive body but the central one, with positions read from the
+// destination (heliocentric) or source (inertial) buffer, only differences are used
+fn whInteraction(index: u32, from_dst: bool) -> vec3<f32> {
+    var aPos = whSrcPos(index);
+    if (from_dst) {
+        aPos = whDstPos(index);
+    }
+    let e = speciesSoftening(particlesSrc.particles[index].species, params.e);
+    var acc = vec3<f32>(0.0, 0.0, 0.0);
+    var i: u32 = 0u;
+    loop {
+        if (i >= naive_params.massive_num) {
+            break;
+        }
+        if (i == index || i == wh.central) {
+            continue;
+        }
+
+        var bPos = whSrcPos(i);
+        if (from_dst) {
+            bPos = whDstPos(i);
+        }
+        let d = bPos - aPos;
+        let r = length(d);
+        acc = acc + params.g * particlesSrc.particles[i].mass * d / (r * r * r + e);
+
+        continuing {
+            i = i + 1u;
+        }
+    }
+    return acc;
+}
+
+fn stumpffC(z: f32) -> f32 {
+    if (z > 0.1) {
+        return (1.0 - cos(sqrt(z))) / z;
+    }
+    if (z < -0.1) {
+        return (cosh(sqrt(-z)) - 1.0) / -z;
+    }
+    return 1.0 / 2.0 - z / 24.0 + z * z / 720.0 - z * z * z / 40320.0;
+}
+
+fn stumpffS(z: f32) -> f32 {
+    if (z > 0.1) {
+        let s = sqrt(z);
+        return (s - sin(s)) / (z * s);
+    }
+    if (z < -0.1) {
+        let s = sqrt(-z);
+        return (sinh(s) - s) / (-z * s);
+    }
+    return 1.0 / 6.0 - z / 120.0 + z * z / 5040.0 - z * z * z / 362880.0;
+}
+
+// advances a Kepler orbit around `mu` by `dt` with the universal variable formulation
+fn keplerDrift(pos: vec3<f32>, vel: vec3<f32>, mu: f32, dt: f32) -> KeplerState {
+    let r0 = length(pos);
+    if (r0 == 0.0 || mu == 0.0) {
+        return KeplerState(pos + vel * dt, vel);
+    }
+    let sqrt_mu = sqrt(mu);
+    let vr0 = dot(pos, vel) / r0;
+    // reciprocal semi-major axis, negative for unbound orbits
+    let alpha = 2.0 / r0 - dot(vel, vel) / mu;
+
+    // solve the universal Kepler equation for chi with Newton's method
+    var chi = sqrt_mu * dt / r0;
+    var i: u32 = 0u;
+    loop {
+        if (i >= 32u) {
+            break;
+        }
+        let z = alpha * chi * chi;
+        let c = stumpffC(z);
+        let s = stumpffS(z);
+        let f = r0 * vr0 / sqrt_mu * chi * chi * c + (1.0 - alpha * r0) * chi * chi * chi * s
+            + r0 * chi - sqrt_mu * dt;
+        let df = r0 * vr0 / sqrt_mu * chi * (1.0 - z * s) + (1.0 - alpha * r0) * chi * chi * c + r0;
+        let delta = f / df;
+        chi = chi - delta;
+        if (abs(delta) <= 0.0000001 * abs(chi)) {
+            break;
+        }
+
+        continuing {
+            i = i + 1u;
+        }
+    }
+
+    // Lagrange coefficients
+    let z = alpha * chi * chi;
+    let c = stumpffC(z);
+    let s = stumpffS(z);
+    let f = 1.0 - chi * chi / r0 * c;
+    let g = dt - chi * chi * chi / sqrt_mu * s;
+    let new_pos = f * pos + g * vel;
+    let r = length(new_pos);
+    let df = sqrt_mu / (r * r0) * (z * chi * s - chi);
+    let dg = 1.0 - chi * chi / r * c;
+    return KeplerState(new_pos, df * pos + dg * vel);
+}
+
+// barycentre and momentum of the inertial source state
+[[stage(compute), workgroup_size(1)]]
+fn wh_frame() {
+    let massive_num = naive_params.massive_num;
+    var central: u32 = 0u;
+    var i: u32 = 0u;
+    loop {
+        if (i >= massive_num) {
+            break;
+        }
+        let _p = particlesSrc.particles[i];
+        if (wh_params.use_id == 1u && _p.id == wh_params.central_id) {
+            central = i;
+            break;
+        }
+        if (_p.mass > particlesSrc.particles[central].mass) {
+            central = i;
+        }
+
+        continuing {
+            i = i + 1u;
+        }
+    }
+    // fall back to the most massive body if the ID isn't a massive particle
+    if (wh_params.use_id == 1u && particlesSrc.particles[central].id != wh_params.central_id) {
+        i = 0u;
+        loop {
+            if (i >= massive_num) {
+                break;
+            }
+            if (particlesSrc.particles[i].mass > particlesSrc.particles[central].mass) {
+                central = i;
+            }
+
+            continuing {
+                i = i + 1u;
+            }
+        }
+    }
+
+    var mass: f32 = 0.0;
+    var moment = vec3<f32>(0.0, 0.0, 0.0);
+    var momentum = vec3<f32>(0.0, 0.0, 0.0);
+    i = 0u;
+    loop {
+        if (i >= massive_num) {
+            break;
+        }
+        let _p = particlesSrc.particles[i];
+        mass = mass + _p.mass;
+        moment = moment + _p.mass * vec3<f32>(_p.px, _p.py, _p.pz);
+        momentum = momentum + _p.mass * vec3<f32>(_p.vx, _p.vy, _p.vz);
+
+        continuing {
+            i = i + 1u;
+        }
+    }
+    let bary_vel = momentum / mass;
+    let _c = particlesSrc.particles[central];
+    wh.central = central;
+    wh.bary_pos = vec4<f32>(moment / mass, mass);
+    wh.bary_vel = vec4<f32>(bary_vel, _c.mass);
+    // the central body's barycentric momentum balances everyone else's
+    wh.momentum = vec4<f32>(-_c.mass * (vec3<f32>(_c.vx, _c.vy, _c.vz) - bary_vel), 0.0);
+}
+
+// converts to democratic heliocentric coordinates, then half kick, half jump and Kepler drift
+[[stage(compute), workgroup_size(64)]]
+fn wh_kick_drift([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
+    let index = global_invocation_id.x;
+    if (index >= params.num_particles) {
+        return;
+    }
+    let _p = particlesSrc.particles[index];
+    if (index == wh.central) {
+        particlesDst.particles[index] = _p;
+        return;
+    }
+    let central_mass = wh.bary_vel.w;
+    var pos = vec3<f32>(_p.px, _p.py, _p.pz) - whSrcPos(wh.central);
+    var vel = vec3<f32>(_p.vx, _p.vy, _p.vz) - wh.bary_vel.xyz;
+
+    vel = vel + whInteraction(index, false) * params.dt / 2.0;
+    pos = pos + wh.momentum.xyz / central_mass * params.dt / 2.0;
+    let kepler = keplerDrift(pos, vel, params.g * central_mass, params.dt);
+
+    particlesDst.particles[index] = Particle(kepler.pos.x, kepler.pos.y, kepler.pos.z, kepler.vel.x, kepler.vel.y, kepler.vel.z, _p.ax, _p.ay, _p.az, _p.mass, _p.id, _p.level, _p.radius, _p.species, _p.charge, _p.potential);
+}
+
+// momentum after the Kepler drift for the second half jump
+[[stage(compute), workgroup_size(1)]]
+fn wh_momentum() {
+    var momentum = vec3<f32>(0.0, 0.0, 0.0);
+    var i: u32 = 0u;
+    loop {
+        if (i >= naive_params.massive_num) {
+            break;
+        }
+        if (i != wh.central) {
+            momentum = momentum + particlesDst.particles[i].mass * whDstVel(i);
+        }
+
+        continuing {
+            i = i + 1u;
+        }
+    }
+    wh.momentum = vec4<f32>(momentum, 0.0);
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn wh_jump([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
+    let index = global_invocation_id.x;
+    if (index >= params.num_particles || index == wh.central) {
+        return;
+    }
+    let pos = whDstPos(index) + wh.momentum.xyz / wh.bary_vel.w * params.dt / 2.0;
+    particlesDst.particles[index].px = pos.x;
+    particlesDst.particles[index].py = pos.y;
+    particlesDst.particles[index].pz = pos.z;
+}
+
+// second half kick, only writes the particle's own velocity and acceleration
+[[stage(compute), workgroup_size(64)]]
+fn wh_kick([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
+    let index = global_invocation_id.x;
+    if (index >= params.num_particles || index == wh.central) {
+        return;
+    }
+    let pos = whDstPos(index);
+    let e = speciesSoftening(particlesDst.particles[index].species, params.e);
+    let r = length(pos);
+    let interaction = whInteraction(index, true);
+    let vel = whDstVel(index) + interaction * params.dt / 2.0;
+    // heliocentric acceleration for timestep criteria and output
+    let acc = interaction - params.g * wh.bary_vel.w * pos / (r * r * r + e);
+    particlesDst.particles[index].vx = vel.x;
+    particlesDst.particles[index].vy = vel.y;
+    particlesDst.particles[index].vz = vel.z;
+    particlesDst.particles[index].ax = acc.x;
+    particlesDst.particles[index].ay = acc.y;
+    particlesDst.particles[index].az = acc.z;
+}
+
+// places the central body so that the barycentre moves uniformly
+[[stage(compute), workgroup_size(1)]]
+fn wh_finish() {
+    let central = wh.central;
+    let central_mass = wh.bary_vel.w;
+    let e = speciesSoftening(particlesDst.particles[central].species, params.e);
+    var moment = vec3<f32>(0.0, 0.0, 0.0);
+    var momentum = vec3<f32>(0.0, 0.0, 0.0);
+    var acc = vec3<f32>(0.0, 0.0, 0.0);
+    var i: u32 = 0u;
+    loop {
+        if (i >= naive_params.massive_num) {
+            break;
+        }
+        if (i != central) {
+            let mass = particlesDst.particles[i].mass;
+            let pos = whDstPos(i);
+            let r = length(pos);
+            moment = moment + mass * pos;
+            momentum = momentum + mass * whDstVel(i);
+            acc = acc + params.g * mass * pos / (r * r * r + e);
+        }
+
+        continuing {
+            i = i + 1u;
+        }
+    }
+    let pos = wh.bary_pos.xyz + wh.bary_vel.xyz * params.dt - moment / wh.bary_pos.w;
+    let vel = wh.bary_vel.xyz - momentum / central_mass;
+    particlesDst.particles[central].px = pos.x;
+    particlesDst.particles[central].py = pos.y;
+    particlesDst.particles[central].pz = pos.z;
+    particlesDst.particles[central].vx = vel.x;
+    particlesDst.particles[central].vy = vel.y;
+    particlesDst.particles[central].vz = vel.z;
+    particlesDst.particles[central].ax = acc.x;
+    particlesDst.particles[central].ay = acc.y;
+    particlesDst.particles[central].az = acc.z;
+}
+
+// back to the inertial frame
+[[stage(compute), workgroup_size(64)]]
+fn wh_restore([[builtin(global_invocation_id)]] global_invocation_id: vec3<u32>) {
+    let index = global_invocation_id.x;
+    if (index >= params.num_particles || index == wh.central) {
+        return;
+    }
+    let pos = whDstPos(index) + whDstPos(wh.central);
+    let vel = whDstVel(index) + wh.bary_vel.xyz;
+    particlesDst.particles[index].px = pos.x;
+    particlesDst.particles[index].py = pos.y;
+    particlesDst.particles[index].pz = pos.z;
+    particlesDst.particles[index].vx = vel.x;
+    particlesDst.particles[index].vy = vel.y;
+    particlesDst.particles[index].vz = vel.z;
+}
@@ -0,0 +1,92 @@
+use anyhow::{ensure, Result};
+
+/// Most species a table can describe, `Particle::species` indexes into it.
+pub const MAX_SPECIES: usize = 8;
+
+pub const DARK_MATTER: u32 = 0;
+pub const STARS: u32 = 1;
+pub const GAS: u32 = 2;
+pub const TRACER: u32 = 3;
+pub const BLACK_HOLE: u32 = 4;
+
+/// Per-species attributes, looked up through `Particle::species`.
+#[derive(Clone, Debug)]
+pub struct Species {
+    pub name: String,
+    /// Softening added to `r^3` for forces felt by this species, `SimParams::e` when `None`
+    pub softening: Option<f32>,
+    /// RGBA colour the renderer draws this species with
+    pub colour: [f32; 4],
+    /// Whether particles of this species are written by exporters
+    pub output: bool,
+}
+
+impl Species {
+    pub fn new(name: &str, colour: [f32; 4]) -> Self {
+        Species {
+            name: name.to_string(),
+            softening: None,
+            colour,
+            output: true,
+        }
+    }
+
+    /// Table indexed by `DARK_MATTER`, `STARS`, `GAS`, `TRACER` and `BLACK_HOLE`. Dark matter is
+    /// drawn like every particle was before species existed.
+    pub fn default_table() -> Vec<Species> {
+        vec![
+            Species::new("dark matter", [1.0, 1.0, 1.0, 0.25]),
+            Species::new("stars", [1.0, 0.9, 0.6, 0.5]),
+            Species::new("gas", [0.4, 0.6, 1.0, 0.35]),
+            Species::new("tracer", [0.5, 1.0, 0.5, 0.25]),
+            Species::new("black hole", [1.0, 0.2, 0.2, 1.0]),
+        ]
+    }
+
+    /// Whether a particle of species `species` is written by exporters under `table`. Species
+    /// outside the table are always written.
+    pub fn is_output(table: &[Species], species: u32) -> bool {
+        table.get(species as usize).is_none_or(|s| s.output)
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct SpeciesUniform {
+    /// x: softening, negative to use `SimParams::e`
+    softening: [[f32; 4]; MAX_SPECIES],
+}
+
+impl Default for SpeciesUniform {
+    fn default() -> Self {
+        SpeciesUniform {
+            softening: [[-1.0, 0.0, 0.0, 0.0]; MAX_SPECIES],
+        }
+    }
+}
+
+impl SpeciesUniform {
+    /// Layout matching `SpeciesTable` in `species.wgsl`. Fails if more than `MAX_SPECIES` are
+    /// given.
+    pub fn new(table: &[Species]) -> Result<Self> {
+        ensure!(
+            table.len() <= MAX_SPECIES,
+            "at most {} species are supported",
+            MAX_SPECIES
+        );
+        let mut uniform = SpeciesUniform::default();
+        for (dst, species) in uniform.softening.iter_mut().zip(table) {
+            dst[0] = species.softening.unwrap_or(-1.0);
+        }
+        Ok(uniform)
+    }
+}
+
+/// Colours of every species slot for the renderer, unlisted slots are drawn white.
+pub(crate) fn species_colours(table: &[Species]) -> [[f32; 4]; MAX_SPECIES] {
+    let mut colours = [[1.0, 1.0, 1.0, 0.25]; MAX_SPECIES];
+    for (dst, species) in colours.iter_mut().zip(table) {
+        *dst = species.colour;
+    }
+    colours
+}
@@ -0,0 +1,247 @@
+use std::borrow::Cow;
+
+use wgpu::util::DeviceExt;
+
+use super::{SimParams, PARTICLES_PER_GROUP};
+
+/// Criteria for choosing a global timestep after every step. The next `dt` is the smallest of
+/// `eta * sqrt(eps / |a|)` and `courant * eps / |v|` over all particles, where `eps` is the
+/// softening length `e^(1/3)`, limited to `max_growth` times the previous `dt` and clamped to
+/// `[dt_min, dt_max]`.
+#[derive(Copy, Clone, Debug)]
+pub struct AdaptiveTimestep {
+    pub eta: f32,
+    pub courant: f32,
+    pub dt_min: f32,
+    pub dt_max: f32,
+    pub max_growth: f32,
+}
+
+impl Default for AdaptiveTimestep {
+    fn default() -> Self {
+        AdaptiveTimestep {
+            eta: 0.05,
+            courant: 0.25,
+            dt_min: 0.00001,
+            dt_max: 0.1,
+            max_growth: 1.25,
+        }
+    }
+}
+
+impl AdaptiveTimestep {
+    /// Timestep for the following step given the one just taken and the minimum over the
+    /// per-particle criteria.
+    pub fn next_dt(&self, dt: f32, criterion_dt: f32) -> f32 {
+        criterion_dt
+            .min(dt * self.max_growth)
+            .clamp(self.dt_min, self.dt_max)
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct TimestepParams {
+    eta: f32,
+    courant: f32,
+    softening: f32,
+    _padding: f32,
+}
+
+/// GPU min-reduction of the per-particle timestep criteria over a simulator's particle buffers.
+pub(crate) struct TimestepReduction {
+    params_buffer: wgpu::Buffer,
+    result_buffer: wgpu::Buffer,
+    read_buffer: wgpu::Buffer,
+    bind_groups: Vec<wgpu::BindGroup>,
+    particle_pipeline: wgpu::ComputePipeline,
+    partials_pipeline: wgpu::ComputePipeline,
+    work_group_count: u32,
+}
+
+impl TimestepReduction {
+    /// Creates one bind group per buffer in `particle_buffers`, selected by index when reducing.
+    pub fn new(
+        device: &wgpu::Device,
+        sim_params_buffer: &wgpu::Buffer,
+        particle_buffers: &[wgpu::Buffer],
+        particle_num: u32,
+    ) -> Self {
+        let params_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Timestep Params Buffer"),
+            size: std::mem::size_of::<TimestepParams>() as _,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let work_group_count = ((particle_num as f32) / (PARTICLES_PER_GROUP as f32)).ceil() as u32;
+        let partials_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Timestep Partials Buffer"),
+            size: (std::mem::size_of::<f32>() * work_group_count.max(1) as usize) as _,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let result_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Timestep Result Buffer"),
+            contents: bytemuck::cast_slice(&[0.0f32]),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+        let read_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Timestep Read Buffer"),
+            size: std::mem::size_of::<f32>() as _,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let compute_module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Timestep Module"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(concat!(
+                include_str!("shaders/particle.wgsl"),
+                include_str!("shaders/timestep.wgsl")
+            ))),
+        });
+
+        let uniform_entry = |binding, size| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: wgpu::BufferSize::new(size as _),
+            },
+            count: None,
+        };
+        let storage_entry = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Timestep Bind Group Layout"),
+            entries: &[
+                uniform_entry(0, std::mem::size_of::<SimParams>()),
+                uniform_entry(1, std::mem::size_of::<TimestepParams>()),
+                storage_entry(2, true),
+                storage_entry(3, false),
+                storage_entry(4, false),
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Timestep Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let particle_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Timestep Particle Pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &compute_module,
+            entry_point: "reduce_particles",
+        });
+        let partials_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Timestep Partials Pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &compute_module,
+            entry_point: "reduce_partials",
+        });
+
+        let bind_groups = particle_buffers
+            .iter()
+            .enumerate()
+            .map(|(i, particle_buffer)| {
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some(&format!("Timestep Bind Group {}", i)),
+                    layout: &bind_group_layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: sim_params_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: params_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: particle_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 3,
+                            resource: partials_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 4,
+                            resource: result_buffer.as_entire_binding(),
+                        },
+                    ],
+                })
+            })
+            .collect();
+
+        Self {
+            params_buffer,
+            result_buffer,
+            read_buffer,
+            bind_groups,
+            particle_pipeline,
+            partials_pipeline,
+            work_group_count,
+        }
+    }
+
+    /// Smallest timestep allowed by `criteria` over the particles in buffer `buffer_ix`. Blocks
+    /// until the reduction has finished.
+    pub fn min_timestep(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        buffer_ix: usize,
+        sim_params: &SimParams,
+        criteria: &AdaptiveTimestep,
+    ) -> f32 {
+        let params = TimestepParams {
+            eta: criteria.eta,
+            courant: criteria.courant,
+            // softening is added to r^3 in the force kernels
+            softening: sim_params.e.cbrt(),
+            _padding: 0.0,
+        };
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[params]));
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Timestep Reduction Command"),
+        });
+        encoder.push_debug_group("timestep reduction");
+        {
+            let mut cpass =
+                encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+            cpass.set_bind_group(0, &self.bind_groups[buffer_ix], &[]);
+            cpass.set_pipeline(&self.particle_pipeline);
+            cpass.dispatch(self.work_group_count, 1, 1);
+            cpass.set_pipeline(&self.partials_pipeline);
+            cpass.dispatch(1, 1, 1);
+        }
+        encoder.pop_debug_group();
+        encoder.copy_buffer_to_buffer(
+            &self.result_buffer,
+            0,
+            &self.read_buffer,
+            0,
+            std::mem::size_of::<f32>() as _,
+        );
+        queue.submit(Some(encoder.finish()));
+
+        let slice = self.read_buffer.slice(..);
+        let map_future = slice.map_async(wgpu::MapMode::Read);
+        device.poll(wgpu::Maintain::Wait);
+        pollster::block_on(map_future).unwrap();
+        let dt = bytemuck::cast_slice::<u8, f32>(&slice.get_mapped_range())[0];
+        self.read_buffer.unmap();
+        dt
+    }
+}
@@ -1,24 +1,40 @@
-use std::{borrow::Cow, collections::VecDeque};
+use std::collections::{HashMap, VecDeque};
 
-use log::warn;
+use log::{info, warn};
 use rayon::prelude::*;
 use wgpu::util::DeviceExt;
 
 use crate::utils::slice_alloc::{Reserve, SliceAlloc};
 
-use super::{AddParams, Particle, SimParams, Simulator};
+use super::{
+    block::BlockStepper, environment::Environment, timestep::TimestepReduction, AddParams,
+    AdaptiveTimestep, BlockTimesteps, ExternalPotential, ForceLaw, Particle, SimParams, Simulator,
+    Species, TRAVERSAL_STACK_SIZE,
+};
 
 pub struct TreeSim {
     sim_params: SimParams,
+    sim_params_buffer: wgpu::Buffer,
     tree_sim_params: TreeSimParams,
+    collisions: bool,
     tree_sim_params_buffer: wgpu::Buffer,
     particle_bind_groups: Vec<wgpu::BindGroup>,
     particle_buffers: Vec<wgpu::Buffer>,
     particle_read_buffer: Option<wgpu::Buffer>,
+    /// particles that fit into the particle buffers
+    capacity: u32,
+    timestep_reduction: TimestepReduction,
     particle_write_buffer: wgpu::Buffer,
     tree_buffer: wgpu::Buffer,
     tree_staging_buffer: Option<wgpu::Buffer>,
+    /// octants that fit into the tree buffers, grown when a tree needs more
+    tree_capacity: usize,
+    compute_module: wgpu::ShaderModule,
+    compute_bind_group_layout: wgpu::BindGroupLayout,
+    environment: Environment,
     compute_pipeline: wgpu::ComputePipeline,
+    force_law: ForceLaw,
+    block_stepper: Option<BlockStepper>,
     work_group_count: u32,
     step_num: usize,
     mappable_primary_buffers: bool,
@@ -33,20 +49,23 @@ impl Simulator for TreeSim {
         mappable_primary_buffers: bool,
         init_fn: fn(&SimParams) -> Vec<Particle>,
     ) -> anyhow::Result<Self> {
+        super::check_particle_limits(device, "TreeSim", sim_params.particle_num.max(1))?;
+        Self::check_tree_limits(device, sim_params.particle_num.max(1))?;
         let sim_params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Sim Params Buffer"),
             contents: bytemuck::cast_slice(&[sim_params]),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
+        let (theta, collisions) = match add_params {
+            AddParams::TreeSimParams { theta, collisions } => (theta, collisions),
+            _ => {
+                warn!("No Theta Value Provided, using default: 0.75");
+                (0.75, false)
+            }
+        };
         let tree_sim_params = TreeSimParams {
-            theta: match add_params {
-                AddParams::TreeSimParams { theta } => theta,
-                _ => {
-                    warn!("No Theta Value Provided, using default: 0.75");
-                    0.75
-                }
-            },
+            theta,
             root_width: 2.0,
         };
         let tree_sim_params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -55,11 +74,6 @@ impl Simulator for TreeSim {
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let compute_module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-            label: Some("Compute Module"),
-            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("shaders/tree.wgsl"))),
-        });
-
         let compute_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("Compute Bind Group Layout"),
@@ -95,8 +109,7 @@ impl Simulator for TreeSim {
                             ty: wgpu::BufferBindingType::Storage { read_only: true },
                             has_dynamic_offset: false,
                             min_binding_size: wgpu::BufferSize::new(
-                                (sim_params.particle_num as usize * std::mem::size_of::<Particle>())
-                                    as _,
+                                std::mem::size_of::<Particle>() as _,
                             ),
                         },
                         count: None,
@@ -108,10 +121,7 @@ impl Simulator for TreeSim {
                             ty: wgpu::BufferBindingType::Storage { read_only: true },
                             has_dynamic_offset: false,
                             min_binding_size: wgpu::BufferSize::new(
-                                (sim_params.particle_num as usize
-                                    * 4
-                                    * std::mem::size_of::<Octant>())
-                                    as _,
+                                std::mem::size_of::<Octant>() as _
                             ),
                         },
                         count: None,
@@ -123,8 +133,7 @@ impl Simulator for TreeSim {
                             ty: wgpu::BufferBindingType::Storage { read_only: false },
                             has_dynamic_offset: false,
                             min_binding_size: wgpu::BufferSize::new(
-                                (sim_params.particle_num as usize * std::mem::size_of::<Particle>())
-                                    as _,
+                                std::mem::size_of::<Particle>() as _,
                             ),
                         },
                         count: None,
@@ -132,126 +141,67 @@ impl Simulator for TreeSim {
                 ],
             });
 
-        let compute_pipeline_layout =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Compute Pipeline Layout"),
-                bind_group_layouts: &[&compute_bind_group_layout],
-                push_constant_ranges: &[],
-            });
+        let environment = Environment::new(device);
 
-        let compute_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-            label: Some("Compute Pipeline"),
-            layout: Some(&compute_pipeline_layout),
-            module: &compute_module,
-            entry_point: "main",
-        });
+        let force_law = ForceLaw::default();
+        let (compute_module, compute_pipeline) = super::create_force_kernel(
+            device,
+            force_law,
+            include_str!("shaders/tree.wgsl"),
+            &[&compute_bind_group_layout, &environment.bind_group_layout],
+        );
 
         let initial_particles = init_fn(&sim_params);
 
-        let mut particle_buffers = Vec::<wgpu::Buffer>::new();
-        let mut particle_bind_groups = Vec::<wgpu::BindGroup>::new();
-        for i in 0..2 {
-            particle_buffers.push(
-                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some(&format!("Particle Buffer {}", i)),
-                    contents: bytemuck::cast_slice(&initial_particles),
-                    usage: wgpu::BufferUsages::VERTEX
-                        | wgpu::BufferUsages::STORAGE
-                        | wgpu::BufferUsages::COPY_SRC
-                        | wgpu::BufferUsages::COPY_DST
-                        | match mappable_primary_buffers {
-                            true => wgpu::BufferUsages::MAP_READ,
-                            false => wgpu::BufferUsages::empty(),
-                        },
-                }),
-            );
-        }
-
-        let particle_read_buffer = if !mappable_primary_buffers {
-            Some(device.create_buffer(&wgpu::BufferDescriptor {
-                label: Some("Particle Read Buffer"),
-                size: (std::mem::size_of::<Particle>() as u32 * sim_params.particle_num) as _,
-                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-                mapped_at_creation: false,
-            }))
-        } else {
-            None
-        };
-
-        let particle_write_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Particle Write Buffer"),
-            size: (std::mem::size_of::<Particle>() as u32 * sim_params.particle_num) as _,
-            usage: wgpu::BufferUsages::MAP_WRITE | wgpu::BufferUsages::COPY_SRC,
-            mapped_at_creation: false,
-        });
-
-        let tree_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Completed Tree Buffer"),
-            size: (std::mem::size_of::<Octant>() as u32 * sim_params.particle_num * 4) as _,
-            usage: wgpu::BufferUsages::VERTEX
-                | wgpu::BufferUsages::STORAGE
-                | wgpu::BufferUsages::COPY_DST
-                | match mappable_primary_buffers {
-                    true => wgpu::BufferUsages::MAP_WRITE,
-                    false => wgpu::BufferUsages::empty(),
-                },
-            mapped_at_creation: false,
-        });
-
-        for i in 0..2 {
-            particle_bind_groups.push(device.create_bind_group(&wgpu::BindGroupDescriptor {
-                label: Some(&format!("Bind Group {}", i)),
-                layout: &compute_bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: sim_params_buffer.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: tree_sim_params_buffer.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 2,
-                        resource: particle_buffers[i].as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 3,
-                        resource: tree_buffer.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 4,
-                        resource: particle_buffers[(i + 1) % 2].as_entire_binding(),
-                    },
-                ],
-            }));
-        }
+        let capacity = sim_params.particle_num;
+        let particle_buffers = super::create_particle_buffers(
+            device,
+            &initial_particles,
+            capacity,
+            mappable_primary_buffers,
+        );
+        let particle_read_buffer =
+            super::create_read_buffer(device, capacity, mappable_primary_buffers);
+        let particle_write_buffer = Self::create_write_buffer(device, capacity);
+        // usually plenty, the buffers grow if a tree needs more
+        let tree_capacity = capacity as usize * 4;
+        let (tree_buffer, tree_staging_buffer) =
+            Self::create_tree_buffers(device, tree_capacity, mappable_primary_buffers);
+        let particle_bind_groups = Self::create_bind_groups(
+            device,
+            &compute_bind_group_layout,
+            &sim_params_buffer,
+            &tree_sim_params_buffer,
+            &particle_buffers,
+            &tree_buffer,
+        );
 
-        let tree_staging_buffer = if !mappable_primary_buffers {
-            Some(device.create_buffer(&wgpu::BufferDescriptor {
-                label: Some("Tree Staging Buffer"),
-                size: (std::mem::size_of::<Octant>() as u32 * sim_params.particle_num * 4) as _,
-                usage: wgpu::BufferUsages::MAP_WRITE | wgpu::BufferUsages::COPY_SRC,
-                mapped_at_creation: false,
-            }))
-        } else {
-            None
-        };
+        let timestep_reduction =
+            TimestepReduction::new(device, &sim_params_buffer, &particle_buffers, capacity);
 
-        let work_group_count =
-            ((sim_params.particle_num as f32) / (super::PARTICLES_PER_GROUP as f32)).ceil() as u32;
+        let work_group_count = sim_params.particle_num.div_ceil(super::PARTICLES_PER_GROUP);
 
         Ok(Self {
             sim_params,
+            sim_params_buffer,
             tree_sim_params,
+            collisions,
             tree_sim_params_buffer,
             particle_bind_groups,
             particle_buffers,
             particle_read_buffer,
+            capacity,
+            timestep_reduction,
             particle_write_buffer,
             tree_buffer,
             tree_staging_buffer,
+            tree_capacity,
+            compute_module,
+            compute_bind_group_layout,
+            environment,
             compute_pipeline,
+            force_law,
+            block_stepper: None,
             work_group_count,
             step_num: 0,
             mappable_primary_buffers,
@@ -282,19 +232,61 @@ impl Simulator for TreeSim {
         let mut write_buffer_mapped = write_buffer_slice.get_mapped_range_mut();
         let mut tree_staging_mapped = tree_staging_slice.get_mapped_range_mut();
 
-        let particle_read_data: &[Particle] = bytemuck::cast_slice(&read_buffer_mapped);
+        let particle_num = self.sim_params.particle_num as usize;
+        let particle_read_data: &[Particle] =
+            &bytemuck::cast_slice(&read_buffer_mapped)[..particle_num];
         let particle_write_data: &mut [Particle] =
             bytemuck::cast_slice_mut(&mut write_buffer_mapped);
         let tree_staging_data: &mut [Octant] = bytemuck::cast_slice_mut(&mut tree_staging_mapped);
 
-        let octree_nodes = self.build_tree(
+        // trees that don't fit into the tree buffer are built here and uploaded after the
+        // buffers have been grown
+        let mut tree_overflow = Vec::new();
+        let (mut octree_nodes, root_width, split_depth) = self.build_tree(
+            device,
             particle_read_data,
             tree_staging_data,
+            &mut tree_overflow,
             queue,
             self.tree_sim_params,
         );
 
-        Self::sort_particles(particle_read_data, particle_write_data, tree_staging_data);
+        let collisions = match self.collisions {
+            true => Self::find_collisions(
+                particle_read_data,
+                Self::built_tree(tree_staging_data, &tree_overflow),
+                root_width,
+                split_depth,
+            ),
+            false => vec![],
+        };
+        let merged_particle_num = if collisions.is_empty() {
+            Self::sort_particles(
+                particle_read_data,
+                &mut particle_write_data[..particle_num],
+                Self::built_tree(tree_staging_data, &tree_overflow),
+            );
+            particle_num
+        } else {
+            // the tree is rebuilt around the merged bodies
+            let merged = Self::merge_collisions(particle_read_data, &collisions);
+            octree_nodes = self
+                .build_tree(
+                    device,
+                    &merged,
+                    tree_staging_data,
+                    &mut tree_overflow,
+                    queue,
+                    self.tree_sim_params,
+                )
+                .0;
+            Self::sort_particles(
+                &merged,
+                &mut particle_write_data[..merged.len()],
+                Self::built_tree(tree_staging_data, &tree_overflow),
+            );
+            merged.len()
+        };
 
         drop(write_buffer_mapped);
         drop(read_buffer_mapped);
@@ -307,6 +299,31 @@ impl Simulator for TreeSim {
             self.particle_read_buffer.as_ref().unwrap().unmap();
             self.tree_staging_buffer.as_ref().unwrap().unmap();
         }
+        if merged_particle_num != particle_num {
+            self.sim_params.particle_num = merged_particle_num as u32;
+            queue.write_buffer(
+                &self.sim_params_buffer,
+                0,
+                bytemuck::cast_slice(&[self.sim_params]),
+            );
+            self.work_group_count = self
+                .sim_params
+                .particle_num
+                .div_ceil(super::PARTICLES_PER_GROUP);
+        }
+        if !tree_overflow.is_empty() {
+            info!(
+                "Tree needs {} octants, growing the tree buffers to {}",
+                octree_nodes,
+                tree_overflow.len()
+            );
+            self.grow_tree_buffers(device, tree_overflow.len());
+            queue.write_buffer(
+                &self.tree_buffer,
+                0,
+                bytemuck::cast_slice(&tree_overflow[..octree_nodes]),
+            );
+        }
 
         let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("Tree Flush/Compute/Render Command"),
@@ -319,40 +336,56 @@ impl Simulator for TreeSim {
                 0,
                 &self.particle_buffers[self.step_num % 2],
                 0,
-                (std::mem::size_of::<Particle>() as u32 * self.sim_params.particle_num as u32) as _,
+                super::particle_bytes(self.sim_params.particle_num),
             );
         }
         encoder.pop_debug_group();
 
-        if !self.mappable_primary_buffers {
+        if !self.mappable_primary_buffers && tree_overflow.is_empty() {
             encoder.push_debug_group("flush tree staging buffer");
             {
                 encoder.copy_buffer_to_buffer(
-                    &self.tree_staging_buffer.as_ref().unwrap(),
+                    self.tree_staging_buffer.as_ref().unwrap(),
                     0,
                     &self.tree_buffer,
                     0,
-                    (std::mem::size_of::<Octant>() as u32 * octree_nodes as u32) as _,
+                    (std::mem::size_of::<Octant>() * octree_nodes) as _,
                 );
             }
             encoder.pop_debug_group();
         }
 
-        encoder.push_debug_group("n-body movement");
-        {
-            let mut cpass =
-                encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
-            cpass.set_pipeline(&self.compute_pipeline);
-            cpass.set_bind_group(0, &self.particle_bind_groups[self.step_num % 2], &[]);
-            cpass.dispatch(self.work_group_count, 1, 1);
+        if let Some(block_stepper) = self.block_stepper.as_mut() {
+            block_stepper.encode(
+                &mut encoder,
+                queue,
+                &self.sim_params,
+                &[
+                    &self.particle_bind_groups[self.step_num % 2],
+                    &self.environment.bind_group,
+                ],
+                &self.particle_buffers[self.step_num % 2],
+                &self.particle_buffers[(self.step_num + 1) % 2],
+                self.work_group_count,
+            );
+        } else {
+            encoder.push_debug_group("n-body movement");
+            {
+                let mut cpass =
+                    encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+                cpass.set_pipeline(&self.compute_pipeline);
+                cpass.set_bind_group(0, &self.particle_bind_groups[self.step_num % 2], &[]);
+                cpass.set_bind_group(1, &self.environment.bind_group, &[]);
+                cpass.dispatch(self.work_group_count, 1, 1);
+            }
+            encoder.pop_debug_group();
         }
-        encoder.pop_debug_group();
         self.step_num += 1;
 
         encoder
     }
 
-    fn dest_particle_slice(&self) -> wgpu::BufferSlice {
+    fn dest_particle_slice(&self) -> wgpu::BufferSlice<'_> {
         self.particle_buffers[(self.step_num + 1) % 2].slice(..)
     }
 
@@ -360,6 +393,192 @@ impl Simulator for TreeSim {
         self.sim_params
     }
 
+    fn read_particles(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<Particle> {
+        super::read_particle_buffer(
+            device,
+            queue,
+            &self.particle_buffers[self.step_num % 2],
+            self.particle_read_buffer.as_ref(),
+            self.sim_params.particle_num,
+        )
+    }
+
+    fn write_particles(
+        &mut self,
+        queue: &wgpu::Queue,
+        particles: &[Particle],
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            particles.len() == self.sim_params.particle_num as usize,
+            "Got {} particles but the simulation holds {}, use replace_particles to change the \
+             count",
+            particles.len(),
+            self.sim_params.particle_num
+        );
+        // particles are re-sorted by locality at the start of the next step
+        queue.write_buffer(
+            &self.particle_buffers[self.step_num % 2],
+            0,
+            bytemuck::cast_slice(particles),
+        );
+        Ok(())
+    }
+
+    fn replace_particles(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        particles: &[Particle],
+    ) -> anyhow::Result<()> {
+        let particle_num = particles.len() as u32;
+        // particles are sorted by locality at the start of the next step
+        if particle_num > self.capacity {
+            // doubling stops at the device limits, beyond them the particles don't fit at all
+            let max_capacity = super::max_particles(device).min(Self::max_tree_particles(device));
+            let capacity = super::grown_capacity(self.capacity, particle_num)
+                .min(max_capacity.max(particle_num));
+            super::check_particle_limits(device, "TreeSim", capacity)?;
+            Self::check_tree_limits(device, capacity)?;
+            self.capacity = capacity;
+            self.particle_buffers = super::create_particle_buffers(
+                device,
+                particles,
+                self.capacity,
+                self.mappable_primary_buffers,
+            );
+            self.particle_read_buffer =
+                super::create_read_buffer(device, self.capacity, self.mappable_primary_buffers);
+            self.particle_write_buffer = Self::create_write_buffer(device, self.capacity);
+            self.grow_tree_buffers(device, self.capacity as usize * 4);
+            self.timestep_reduction = TimestepReduction::new(
+                device,
+                &self.sim_params_buffer,
+                &self.particle_buffers,
+                self.capacity,
+            );
+            if let Some(block_stepper) = self.block_stepper.as_mut() {
+                block_stepper.resize(device, self.capacity);
+            }
+        } else {
+            queue.write_buffer(
+                &self.particle_buffers[self.step_num % 2],
+                0,
+                bytemuck::cast_slice(particles),
+            );
+        }
+        self.sim_params.particle_num = particle_num;
+        queue.write_buffer(
+            &self.sim_params_buffer,
+            0,
+            bytemuck::cast_slice(&[self.sim_params]),
+        );
+        self.work_group_count = particle_num.div_ceil(super::PARTICLES_PER_GROUP);
+        Ok(())
+    }
+
+    fn set_sim_params(&mut self, queue: &wgpu::Queue, sim_params: SimParams) {
+        self.sim_params = SimParams {
+            particle_num: self.sim_params.particle_num,
+            ..sim_params
+        };
+        queue.write_buffer(
+            &self.sim_params_buffer,
+            0,
+            bytemuck::cast_slice(&[self.sim_params]),
+        );
+    }
+
+    fn set_add_params(
+        &mut self,
+        _queue: &wgpu::Queue,
+        add_params: AddParams,
+    ) -> anyhow::Result<()> {
+        match add_params {
+            AddParams::TreeSimParams { theta, collisions } => {
+                // uploaded together with the root width by the next build_tree
+                self.tree_sim_params.theta = theta;
+                self.collisions = collisions;
+                Ok(())
+            }
+            _ => anyhow::bail!("TreeSim only accepts AddParams::TreeSimParams"),
+        }
+    }
+
+    fn min_timestep(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        criteria: &AdaptiveTimestep,
+    ) -> f32 {
+        self.timestep_reduction.min_timestep(
+            device,
+            queue,
+            self.step_num % 2,
+            &self.sim_params,
+            criteria,
+        )
+    }
+
+    fn set_block_timesteps(
+        &mut self,
+        device: &wgpu::Device,
+        block_timesteps: Option<BlockTimesteps>,
+    ) {
+        self.block_stepper = block_timesteps.map(|config| {
+            BlockStepper::new(
+                device,
+                config,
+                &self.compute_module,
+                &[
+                    &self.compute_bind_group_layout,
+                    &self.environment.bind_group_layout,
+                ],
+                self.capacity,
+            )
+        });
+    }
+
+    fn set_force_law(&mut self, device: &wgpu::Device, force_law: ForceLaw) -> anyhow::Result<()> {
+        force_law.validate()?;
+        if force_law == self.force_law {
+            return Ok(());
+        }
+        let bind_group_layouts = [
+            &self.compute_bind_group_layout,
+            &self.environment.bind_group_layout,
+        ];
+        (self.compute_module, self.compute_pipeline) = super::create_force_kernel(
+            device,
+            force_law,
+            include_str!("shaders/tree.wgsl"),
+            &bind_group_layouts,
+        );
+        if let Some(block_stepper) = self.block_stepper.as_mut() {
+            block_stepper.set_module(device, &self.compute_module, &bind_group_layouts);
+        }
+        self.force_law = force_law;
+        Ok(())
+    }
+
+    fn set_external_potentials(
+        &mut self,
+        queue: &wgpu::Queue,
+        potentials: &[ExternalPotential],
+    ) -> anyhow::Result<()> {
+        self.environment.write_potentials(queue, potentials)
+    }
+
+    fn set_species(&mut self, queue: &wgpu::Queue, species: &[Species]) -> anyhow::Result<()> {
+        self.environment.write_species(queue, species)
+    }
+
+    fn step_dt(&self) -> f32 {
+        match &self.block_stepper {
+            Some(block_stepper) => block_stepper.substep_dt(&self.sim_params),
+            None => self.sim_params.dt,
+        }
+    }
+
     fn cleanup(&mut self) {
         self.alloc_arena.reset();
     }
@@ -367,6 +586,14 @@ impl Simulator for TreeSim {
 
 type BVec<'a, T> = bumpalo::collections::Vec<'a, T>;
 
+/// Nodes more than this many splits below the root are split without looking at positions.
+pub(crate) const SPLIT_DEPTH: u32 = 20;
+
+/// Most particles whose tree, split by position only at the root, fits the traversal stack.
+/// Every level below the root keeps up to 7 siblings waiting on the stack, and spreading
+/// particles evenly over 8 children leaves `log8(n)` levels below the root's children.
+const MAX_TRAVERSABLE_PARTICLES: usize = 1 << (3 * ((TRAVERSAL_STACK_SIZE - 1) / 7 - 1));
+
 #[derive(Debug)]
 struct Partition<'a> {
     center: [f32; 3],
@@ -376,11 +603,146 @@ struct Partition<'a> {
 }
 
 impl TreeSim {
+    fn create_write_buffer(device: &wgpu::Device, capacity: u32) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Particle Write Buffer"),
+            size: super::particle_bytes(capacity.max(1)),
+            usage: wgpu::BufferUsages::MAP_WRITE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Tree buffer read by the force kernel and its staging buffer if the tree buffer can't be
+    /// mapped, both with room for `tree_capacity` octants.
+    fn create_tree_buffers(
+        device: &wgpu::Device,
+        tree_capacity: usize,
+        mappable_primary_buffers: bool,
+    ) -> (wgpu::Buffer, Option<wgpu::Buffer>) {
+        let size = (std::mem::size_of::<Octant>() * tree_capacity.max(4)) as _;
+        let tree_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Completed Tree Buffer"),
+            size,
+            usage: wgpu::BufferUsages::VERTEX
+                | wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_DST
+                | match mappable_primary_buffers {
+                    true => wgpu::BufferUsages::MAP_WRITE,
+                    false => wgpu::BufferUsages::empty(),
+                },
+            mapped_at_creation: false,
+        });
+        let tree_staging_buffer = match mappable_primary_buffers {
+            true => None,
+            false => Some(device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Tree Staging Buffer"),
+                size,
+                usage: wgpu::BufferUsages::MAP_WRITE | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            })),
+        };
+        (tree_buffer, tree_staging_buffer)
+    }
+
+    /// Recreates the tree buffers with room for at least `tree_capacity` octants along with the
+    /// bind groups that reference them. The new buffers start out empty.
+    fn grow_tree_buffers(&mut self, device: &wgpu::Device, tree_capacity: usize) {
+        self.tree_capacity = self.tree_capacity.max(tree_capacity);
+        let (tree_buffer, tree_staging_buffer) =
+            Self::create_tree_buffers(device, self.tree_capacity, self.mappable_primary_buffers);
+        self.tree_buffer = tree_buffer;
+        self.tree_staging_buffer = tree_staging_buffer;
+        self.particle_bind_groups = Self::create_bind_groups(
+            device,
+            &self.compute_bind_group_layout,
+            &self.sim_params_buffer,
+            &self.tree_sim_params_buffer,
+            &self.particle_buffers,
+            &self.tree_buffer,
+        );
+    }
+
+    /// Most octants a tree buffer can hold while still fitting into one storage binding.
+    fn max_binding_octants(device: &wgpu::Device) -> usize {
+        device.limits().max_storage_buffer_binding_size as usize / std::mem::size_of::<Octant>()
+    }
+
+    /// Most particles whose four octants each still fit into one storage binding, and whose
+    /// tree can be traversed with `TRAVERSAL_STACK_SIZE` entries.
+    fn max_tree_particles(device: &wgpu::Device) -> u32 {
+        (Self::max_binding_octants(device) / 4).min(MAX_TRAVERSABLE_PARTICLES) as u32
+    }
+
+    /// Fails with a readable error if the tree buffer for `capacity` particles can't be bound
+    /// whole on `device`, or if `tree.wgsl`'s stack is too small for their tree. Four octants
+    /// per particle and the stack always hold a tree split by position only at the root, which
+    /// `build_tree` falls back to when a deeper tree doesn't fit.
+    fn check_tree_limits(device: &wgpu::Device, capacity: u32) -> anyhow::Result<()> {
+        let octants = capacity as usize * 4;
+        let max_octants = Self::max_binding_octants(device);
+        anyhow::ensure!(
+            octants <= max_octants,
+            "TreeSim reserves {} octants for the tree of {} particles but the device's storage \
+             bindings hold at most {} octants",
+            octants,
+            capacity,
+            max_octants
+        );
+        anyhow::ensure!(
+            capacity as usize <= MAX_TRAVERSABLE_PARTICLES,
+            "TreeSim's traversal stack of {} entries only fits trees of up to {} particles, not {}",
+            TRAVERSAL_STACK_SIZE,
+            MAX_TRAVERSABLE_PARTICLES,
+            capacity
+        );
+        Ok(())
+    }
+
+    fn create_bind_groups(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        sim_params_buffer: &wgpu::Buffer,
+        tree_sim_params_buffer: &wgpu::Buffer,
+        particle_buffers: &[wgpu::Buffer],
+        tree_buffer: &wgpu::Buffer,
+    ) -> Vec<wgpu::BindGroup> {
+        (0..2)
+            .map(|i| {
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some(&format!("Bind Group {}", i)),
+                    layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: sim_params_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: tree_sim_params_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: particle_buffers[i].as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 3,
+                            resource: tree_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 4,
+                            resource: particle_buffers[(i + 1) % 2].as_entire_binding(),
+                        },
+                    ],
+                })
+            })
+            .collect()
+    }
+
     fn get_particle_read_slice(
         &self,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-    ) -> wgpu::BufferSlice {
+    ) -> wgpu::BufferSlice<'_> {
         if self.mappable_primary_buffers {
             // buffer copy is unnecessary
             self.particle_buffers[self.step_num % 2].slice(..)
@@ -392,9 +754,9 @@ impl TreeSim {
                 read_encoder.copy_buffer_to_buffer(
                     &self.particle_buffers[self.step_num % 2],
                     0,
-                    &self.particle_read_buffer.as_ref().unwrap(),
+                    self.particle_read_buffer.as_ref().unwrap(),
                     0,
-                    (std::mem::size_of::<Particle>() as u32 * self.sim_params.particle_num) as _,
+                    super::particle_bytes(self.sim_params.particle_num),
                 );
             }
             queue.submit(Some(read_encoder.finish()));
@@ -406,7 +768,7 @@ impl TreeSim {
         &self,
         _device: &wgpu::Device,
         _queue: &wgpu::Queue,
-    ) -> wgpu::BufferSlice {
+    ) -> wgpu::BufferSlice<'_> {
         if self.mappable_primary_buffers {
             self.tree_buffer.slice(..)
         } else {
@@ -414,15 +776,85 @@ impl TreeSim {
         }
     }
 
+    /// Builds the tree into the mapped `tree_data`, or into `tree_overflow` if it doesn't fit
+    /// there or an earlier tree of this step already didn't. `tree_overflow` is doubled until
+    /// the tree fits or reaches the storage binding limit. Trees that still don't fit, or that
+    /// need a deeper traversal stack than `tree.wgsl` has, are split by position fewer levels
+    /// deep. Returns the node count, the root width and the split depth.
     fn build_tree(
         &self,
+        device: &wgpu::Device,
         particle_data: &[Particle],
         tree_data: &mut [Octant],
+        tree_overflow: &mut Vec<Octant>,
         queue: &wgpu::Queue,
-        mut tree_sim_params: TreeSimParams,
-    ) -> usize {
+        tree_sim_params: TreeSimParams,
+    ) -> (usize, f32, u32) {
+        let max_octants = Self::max_binding_octants(device);
+        let mut split_depth = SPLIT_DEPTH;
+        let (nodes, root_width) = loop {
+            let target: &mut [Octant] = match tree_overflow.is_empty() {
+                true => &mut *tree_data,
+                false => tree_overflow,
+            };
+            let reason = match Self::build_octree(
+                particle_data,
+                target,
+                &self.alloc_arena,
+                split_depth,
+            ) {
+                Some((nodes, root_width)) => {
+                    let stack_depth = Self::traversal_stack_depth(&target[..nodes]);
+                    if stack_depth <= TRAVERSAL_STACK_SIZE {
+                        break (nodes, root_width);
+                    }
+                    format!("needs a traversal stack of {} entries", stack_depth)
+                }
+                None if target.len() < max_octants => {
+                    let len = (target.len().max(1) * 2).min(max_octants);
+                    tree_overflow.resize(len, Octant::default());
+                    continue;
+                }
+                None => format!("exceeds the device's limit of {} octants", max_octants),
+            };
+            // `check_tree_limits` made sure that a tree split only once by position fits
+            assert!(split_depth > 0, "Tree without position splits doesn't fit");
+            split_depth /= 2;
+            warn!("Tree {}, splitting only {} levels deep", reason, split_depth);
+        };
+        // write new root bounds data for gpu force calculation
+        queue.write_buffer(
+            &self.tree_sim_params_buffer,
+            0,
+            bytemuck::cast_slice(&[TreeSimParams {
+                theta: tree_sim_params.theta,
+                root_width,
+            }]),
+        );
+        (nodes, root_width, split_depth)
+    }
+
+    /// The tree of the last `build_tree`.
+    fn built_tree<'a>(tree_data: &'a [Octant], tree_overflow: &'a [Octant]) -> &'a [Octant] {
+        match tree_overflow.is_empty() {
+            true => tree_data,
+            false => tree_overflow,
+        }
+    }
+
+    /// Builds the octree of the massive particles into `tree_data`, returning the node count and
+    /// the root width, or `None` if the nodes don't fit. The root is centred at the origin and
+    /// node 0. Nodes more than `split_depth` splits below the root are degenerate.
+    pub(crate) fn build_octree(
+        particle_data: &[Particle],
+        tree_data: &mut [Octant],
+        alloc_arena: &bumpalo::Bump,
+        split_depth: u32,
+    ) -> Option<(usize, f32)> {
+        // tracers don't source gravity so they are left out of the tree entirely
         let bound = particle_data
             .par_iter()
+            .filter(|p| !p.is_tracer())
             .cloned()
             .reduce(
                 || Particle {
@@ -430,6 +862,12 @@ impl TreeSim {
                     velocity: [0.0; 3],
                     acceleration: [0.0; 3],
                     mass: 1.0,
+                    id: 0,
+                    level: 0,
+                    radius: 0.0,
+                    species: 0,
+                    charge: 0.0,
+                    potential: 0.0,
                 },
                 |a, b| Particle {
                     position: [
@@ -440,70 +878,98 @@ impl TreeSim {
                     velocity: [0.0; 3],
                     acceleration: [0.0; 3],
                     mass: 1.0,
+                    id: 0,
+                    level: 0,
+                    radius: 0.0,
+                    species: 0,
+                    charge: 0.0,
+                    potential: 0.0,
                 },
             )
             .position;
         let bound = bound[0].max(bound[1]).max(bound[2]);
-        // write new root bounds data for gpu force calculation
-        tree_sim_params = TreeSimParams {
-            theta: tree_sim_params.theta,
-            root_width: bound * 2.0,
-        };
-        queue.write_buffer(
-            &self.tree_sim_params_buffer,
-            0,
-            bytemuck::cast_slice(&[tree_sim_params]),
-        );
+        let root_width = bound * 2.0;
         let bound = [bound; 3];
         let mut part_queue = VecDeque::new();
         // initialize slice allocator
         let mut tree_alloc = SliceAlloc::wrap(tree_data);
-        let root_ix = tree_alloc.write(Octant::default());
-        // create root partition (all particles)
+        let root_ix = tree_alloc.try_write(Octant::default())?;
+        let massive_ix = BVec::from_iter_in(
+            (0..particle_data.len()).filter(|&ix| !particle_data[ix].is_tracer()),
+            alloc_arena,
+        );
+        match massive_ix.len() {
+            // empty root (zero mass) is skipped by the force kernel
+            0 => return Some((tree_alloc.len(), root_width)),
+            1 => {
+                let particle = particle_data[massive_ix[0]];
+                let mut leaf_octant = Octant {
+                    cog: particle.position,
+                    mass: particle.mass,
+                    charge: particle.charge,
+                    bodies: 1,
+                    ..Default::default()
+                };
+                leaf_octant.first_child = massive_ix[0] as u32;
+                tree_alloc[root_ix] = leaf_octant;
+                return Some((tree_alloc.len(), root_width));
+            }
+            _ => {}
+        }
+        // create root partition (all massive particles)
         part_queue.push_back(Partition {
             center: [0.0; 3],
             width: bound[0] * 2.0,
             octant_ix: Some(root_ix),
-            particles_ix: Some(BVec::from_iter_in(
-                0..particle_data.len(),
-                &self.alloc_arena,
-            )),
+            particles_ix: Some(massive_ix),
         });
         // while there are partitions to process
         while let Some(part) = part_queue.pop_front() {
             // create all possible child partitions (not always added to queue)
             let mut child_partitions: Vec<Partition> = (0..8)
-                .map(|ix| Partition {
-                    center: Self::shift_node_center(&part.center, part.width, ix),
-                    width: part.width / 2.0,
-                    octant_ix: None,
-                    particles_ix: None,
+                .map(|ix| {
+                    let (center, width) =
+                        Self::child_bounds(&part.center, part.width, root_width, split_depth, ix);
+                    Partition {
+                        center,
+                        width,
+                        octant_ix: None,
+                        particles_ix: None,
+                    }
                 })
                 .collect();
+            let degenerate = Self::is_degenerate(part.width, root_width, split_depth);
             // partition's octant
             let mut octant = Octant::default();
             // calculate octant data and particle child subdivisions
-            for particle_ix in part.particles_ix.as_ref().unwrap() {
+            for (k, particle_ix) in part.particles_ix.as_ref().unwrap().iter().enumerate() {
                 let p = particle_data[*particle_ix];
                 octant.cog[0] += p.position[0] * p.mass;
                 octant.cog[1] += p.position[1] * p.mass;
                 octant.cog[2] += p.position[2] * p.mass;
                 octant.mass += p.mass;
-                let child_ix = Self::decide_octant(&part.center, &p.position);
+                octant.charge += p.charge;
+                // (nearly) coincident particles can't be separated by position, so they are
+                // spread over the children instead
+                let child_ix = match degenerate {
+                    true => k % 8,
+                    false => Self::decide_octant(&part.center, &p.position),
+                };
                 if let Some(ref mut particles_ix) = child_partitions[child_ix].particles_ix {
                     // child particles list already exists
                     particles_ix.push(*particle_ix);
                 } else {
                     // needs to be created
                     child_partitions[child_ix].particles_ix =
-                        Some(BVec::from_iter_in(Some(*particle_ix), &self.alloc_arena));
+                        Some(BVec::from_iter_in(Some(*particle_ix), alloc_arena));
                 }
             }
             octant.bodies += part.particles_ix.unwrap().len() as u32;
             octant.cog[0] /= octant.mass;
             octant.cog[1] /= octant.mass;
             octant.cog[2] /= octant.mass;
-            // only add new partitions if non-leaf node
+            // only add new partitions if non-leaf node, the children are allocated back to back
+            // so that the first index and the mask locate all of them
             for (i, mut child_part) in child_partitions.into_iter().enumerate() {
                 let part_count = child_part
                     .particles_ix
@@ -514,9 +980,12 @@ impl TreeSim {
                 if part_count == 0 {
                     continue;
                 }
-                let child_oct_handle = tree_alloc.write(Octant::default());
+                let child_oct_handle = tree_alloc.try_write(Octant::default())?;
                 let child_oct_ix: usize = (&child_oct_handle).into();
-                octant.children[i] = child_oct_ix as u32;
+                if octant.child_mask == 0 {
+                    octant.first_child = child_oct_ix as u32;
+                }
+                octant.child_mask |= 1 << i;
                 match part_count {
                     1 => {
                         // leaf node (complete octant processing and finish)
@@ -525,11 +994,12 @@ impl TreeSim {
                         let mut leaf_octant = Octant {
                             cog: leaf_particle.position,
                             mass: leaf_particle.mass,
+                            charge: leaf_particle.charge,
                             bodies: 1,
                             ..Default::default()
                         };
                         // set first child to particle index for sorting particles by locality
-                        leaf_octant.children[0] = child_part.particles_ix.unwrap()[0] as u32;
+                        leaf_octant.first_child = child_part.particles_ix.unwrap()[0] as u32;
                         tree_alloc[child_oct_handle] = leaf_octant;
                     }
                     _ => {
@@ -539,10 +1009,192 @@ impl TreeSim {
                     }
                 };
             }
+            if degenerate {
+                octant.child_mask |= Octant::DEGENERATE;
+            }
             // write octant to array
             tree_alloc[part.octant_ix.unwrap()] = octant;
         }
-        tree_alloc.len()
+        Some((tree_alloc.len(), root_width))
+    }
+
+    /// Stack entries `tree.wgsl`'s traversal needs for `tree` when it opens every node.
+    /// Children are pushed in octant order and popped in reverse, so a child waits above every
+    /// sibling pushed before it.
+    pub(crate) fn traversal_stack_depth(tree: &[Octant]) -> usize {
+        fn below(tree: &[Octant], ix: usize) -> usize {
+            let octant = &tree[ix];
+            if octant.bodies <= 1 {
+                return 1;
+            }
+            octant
+                .children()
+                .enumerate()
+                .map(|(waiting, (_, child))| waiting + below(tree, child as usize))
+                .max()
+                .unwrap_or(1)
+        }
+        below(tree, 0)
+    }
+
+    /// Whether a node is too small to split by position any further.
+    #[inline]
+    fn is_degenerate(node_width: f32, root_width: f32, split_depth: u32) -> bool {
+        node_width < root_width / (1u32 << split_depth) as f32
+    }
+
+    /// Center and width of a child node. Degenerate nodes pass their own bounds on, since their
+    /// particles aren't split by position.
+    #[inline]
+    pub(crate) fn child_bounds(
+        node_center: &[f32; 3],
+        node_width: f32,
+        root_width: f32,
+        split_depth: u32,
+        child_octant: usize,
+    ) -> ([f32; 3], f32) {
+        match Self::is_degenerate(node_width, root_width, split_depth) {
+            true => (*node_center, node_width),
+            false => (
+                Self::shift_node_center(node_center, node_width, child_octant),
+                node_width / 2.0,
+            ),
+        }
+    }
+
+    /// Pairs `(i, j)` with `i < j` of massive particles closer than the sum of their radii, using
+    /// the tree built from `particle_data` for the neighbour search.
+    fn find_collisions(
+        particle_data: &[Particle],
+        tree_data: &[Octant],
+        root_width: f32,
+        split_depth: u32,
+    ) -> Vec<(usize, usize)> {
+        let max_radius = particle_data
+            .par_iter()
+            .filter(|p| !p.is_tracer())
+            .map(|p| p.radius)
+            .reduce(|| 0.0, f32::max);
+        if max_radius <= 0.0 || tree_data[0].bodies == 0 {
+            return vec![];
+        }
+        (0..particle_data.len())
+            .into_par_iter()
+            .filter(|&i| !particle_data[i].is_tracer())
+            .flat_map_iter(|i| {
+                let p = particle_data[i];
+                // any partner has to lie within this distance
+                let reach = p.radius + max_radius;
+                let mut pairs = vec![];
+                let mut stack = vec![(0usize, [0.0f32; 3], root_width)];
+                while let Some((octant_ix, center, width)) = stack.pop() {
+                    let octant = tree_data[octant_ix];
+                    if octant.bodies == 1 {
+                        let j = octant.first_child as usize;
+                        let q = particle_data[j];
+                        if j > i && super::distance(&p.position, &q.position) < p.radius + q.radius
+                        {
+                            pairs.push((i, j));
+                        }
+                        continue;
+                    }
+                    // distance from the particle to the node's bounding box
+                    let outside = (0..3)
+                        .map(|d| ((p.position[d] - center[d]).abs() - width / 2.0).max(0.0))
+                        .map(|d| d * d)
+                        .sum::<f32>()
+                        .sqrt();
+                    if outside > reach {
+                        continue;
+                    }
+                    for (child, child_ix) in octant.children() {
+                        let (child_center, child_width) =
+                            Self::child_bounds(&center, width, root_width, split_depth, child);
+                        stack.push((child_ix as usize, child_center, child_width));
+                    }
+                }
+                pairs
+            })
+            .collect()
+    }
+
+    /// Merges every connected group of colliding particles into one body conserving mass,
+    /// momentum and centre of mass. The merged body keeps the ID of its most massive member and
+    /// the volume of all members. Particles that didn't collide are kept in order, followed by
+    /// the merged bodies.
+    fn merge_collisions(
+        particle_data: &[Particle],
+        collisions: &[(usize, usize)],
+    ) -> Vec<Particle> {
+        // union-find over the colliding particles
+        let mut parents: HashMap<usize, usize> = HashMap::new();
+        fn find(parents: &mut HashMap<usize, usize>, ix: usize) -> usize {
+            let parent = *parents.entry(ix).or_insert(ix);
+            if parent == ix {
+                return ix;
+            }
+            let root = find(parents, parent);
+            parents.insert(ix, root);
+            root
+        }
+        for &(a, b) in collisions {
+            let root_a = find(&mut parents, a);
+            let root_b = find(&mut parents, b);
+            parents.insert(root_a, root_b);
+        }
+        let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+        let members: Vec<usize> = parents.keys().copied().collect();
+        for ix in members {
+            let root = find(&mut parents, ix);
+            groups.entry(root).or_default().push(ix);
+        }
+
+        let mut merged: Vec<Particle> = particle_data
+            .iter()
+            .enumerate()
+            .filter(|(ix, _)| !parents.contains_key(ix))
+            .map(|(_, p)| *p)
+            .collect();
+        for group in groups.values() {
+            let mass: f32 = group.iter().map(|&ix| particle_data[ix].mass).sum();
+            let weighted = |field: fn(&Particle) -> [f32; 3]| {
+                let mut sum = [0.0f32; 3];
+                for &ix in group {
+                    let p = &particle_data[ix];
+                    for (s, v) in sum.iter_mut().zip(field(p)) {
+                        *s += v * p.mass / mass;
+                    }
+                }
+                sum
+            };
+            let heaviest = group
+                .iter()
+                .map(|&ix| particle_data[ix])
+                .max_by(|a, b| a.mass.total_cmp(&b.mass).then(b.id.cmp(&a.id)))
+                .unwrap();
+            merged.push(Particle {
+                position: weighted(|p| p.position),
+                velocity: weighted(|p| p.velocity),
+                acceleration: weighted(|p| p.acceleration),
+                mass,
+                id: heaviest.id,
+                species: heaviest.species,
+                charge: group.iter().map(|&ix| particle_data[ix].charge).sum(),
+                // unknown until the next force evaluation
+                potential: 0.0,
+                level: group
+                    .iter()
+                    .map(|&ix| particle_data[ix].level)
+                    .min()
+                    .unwrap(),
+                radius: group
+                    .iter()
+                    .map(|&ix| particle_data[ix].radius.powi(3))
+                    .sum::<f32>()
+                    .cbrt(),
+            });
+        }
+        merged
     }
 
     #[inline]
@@ -566,37 +1218,47 @@ impl TreeSim {
         particles_dst: &mut [Particle],
         tree_data: &[Octant],
     ) {
-        Self::sort_particles_recursive(tree_data[0], particles_src, particles_dst, tree_data);
+        // massive particles in tree order followed by the tracers in their previous order
+        let (massive_dst, tracers_dst) = particles_dst.split_at_mut(tree_data[0].bodies as usize);
+        if !massive_dst.is_empty() {
+            Self::write_in_tree_order(
+                tree_data[0],
+                &|ix| particles_src[ix as usize],
+                massive_dst,
+                tree_data,
+            );
+        }
+        let tracers: Vec<Particle> = particles_src
+            .par_iter()
+            .filter(|p| p.is_tracer())
+            .cloned()
+            .collect();
+        tracers_dst.copy_from_slice(&tracers);
     }
 
-    fn sort_particles_recursive(
+    /// Writes `leaf_value` of every particle index below `octant` to `dst` in depth-first leaf
+    /// order, so that particles close in the tree end up close in memory.
+    pub(crate) fn write_in_tree_order<T: Send>(
         octant: Octant,
-        particles_src: &[Particle],
-        particles_dst: &mut [Particle],
+        leaf_value: &(impl Fn(u32) -> T + Sync),
+        dst: &mut [T],
         tree_data: &[Octant],
     ) {
         if octant.bodies == 1 {
-            particles_dst[0] = particles_src[octant.children[0] as usize];
+            dst[0] = leaf_value(octant.first_child);
         } else {
             let mut slices = vec![];
-            let mut remaining = particles_dst;
-            for child_ix in octant.children {
-                if child_ix != 0 {
-                    let child_octant = tree_data[child_ix as usize];
-                    let (a_slice, b_slice) = remaining.split_at_mut(child_octant.bodies as usize);
-                    remaining = b_slice;
-                    slices.push((child_octant, a_slice));
-                }
+            let mut remaining = dst;
+            for (_, child_ix) in octant.children() {
+                let child_octant = tree_data[child_ix as usize];
+                let (a_slice, b_slice) = remaining.split_at_mut(child_octant.bodies as usize);
+                remaining = b_slice;
+                slices.push((child_octant, a_slice));
             }
             slices
                 .par_iter_mut()
                 .for_each(|(child_octant, child_slice)| {
-                    Self::sort_particles_recursive(
-                        *child_octant,
-                        particles_src,
-                        child_slice,
-                        tree_data,
-                    );
+                    Self::write_in_tree_order(*child_octant, leaf_value, child_slice, tree_data);
                 });
         }
     }
@@ -604,9 +1266,9 @@ impl TreeSim {
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
-struct Octant {
+pub(crate) struct Octant {
     /// Child Octant Positions:
-    /// ```
+    /// ```text
     /// Front: -z   Back: +z
     /// |---|---|   |---|---|
     /// | 2 | 3 |   | 6 | 7 |
@@ -614,11 +1276,37 @@ struct Octant {
     /// | 0 | 1 |   | 4 | 5 |
     /// |---|---|   |---|---|
     /// ```
-    cog: [f32; 3],
-    mass: f32,
+    pub(crate) cog: [f32; 3],
+    pub(crate) mass: f32,
+    /// total charge, placed at the centre of mass
+    pub(crate) charge: f32,
     // if bodies == 1 then read data from particles array (first child ix)
-    bodies: u32,
-    children: [u32; 8],
+    pub(crate) bodies: u32,
+    /// Index of the first child, the other occupied children follow it in octant order. Leaves
+    /// hold their particle index here instead.
+    pub(crate) first_child: u32,
+    /// Bit `k` is set if child octant `k` is occupied, zero for leaves. `DEGENERATE` marks nodes
+    /// whose children keep the node's width, see `TreeSim::child_bounds`.
+    pub(crate) child_mask: u32,
+}
+
+impl Octant {
+    /// `child_mask` flag of nodes too small to split by position.
+    pub(crate) const DEGENERATE: u32 = 1 << 8;
+
+    #[inline]
+    pub(crate) fn is_degenerate(&self) -> bool {
+        self.child_mask & Self::DEGENERATE != 0
+    }
+
+    /// Occupied children as `(child octant, node index)` pairs in octant order.
+    #[inline]
+    pub(crate) fn children(&self) -> impl Iterator<Item = (usize, u32)> {
+        let child_mask = self.child_mask;
+        (0..8)
+            .filter(move |child| child_mask >> child & 1 == 1)
+            .zip(self.first_child..)
+    }
 }
 
 #[repr(C)]
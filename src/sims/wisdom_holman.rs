@@ -0,0 +1,176 @@
+use wgpu::util::DeviceExt;
+
+use super::ForceLaw;
+
+/// Wisdom-Holman mapping in democratic heliocentric coordinates for systems with one dominant
+/// central body (e.g. `disc_init`). Every other body drifts on an exact Kepler orbit around it
+/// and is kicked by the remaining massive bodies, so `dt` only has to resolve their mutual
+/// perturbations. External potentials are not applied.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct WisdomHolman {
+    /// ID of the central body, the most massive body when `None` or when no massive particle has
+    /// this ID
+    pub central_id: Option<u32>,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct WisdomHolmanParams {
+    central_id: u32,
+    use_id: u32,
+    _padding: [u32; 2],
+}
+
+/// Size of `WisdomHolmanState` in `wisdom_holman.wgsl`.
+const STATE_SIZE: usize = std::mem::size_of::<[[f32; 4]; 4]>();
+
+/// Runs the Wisdom-Holman entry points of the naive kernel in place of its main pipeline.
+pub(crate) struct WisdomHolmanStepper {
+    bind_group: wgpu::BindGroup,
+    frame_pipeline: wgpu::ComputePipeline,
+    kick_drift_pipeline: wgpu::ComputePipeline,
+    momentum_pipeline: wgpu::ComputePipeline,
+    jump_pipeline: wgpu::ComputePipeline,
+    kick_pipeline: wgpu::ComputePipeline,
+    finish_pipeline: wgpu::ComputePipeline,
+    restore_pipeline: wgpu::ComputePipeline,
+}
+
+impl WisdomHolmanStepper {
+    /// `kernel_bind_group_layouts` are the naive kernel's own layouts (groups 0 and 1), the
+    /// Wisdom-Holman bindings follow them.
+    pub fn new(
+        device: &wgpu::Device,
+        config: WisdomHolman,
+        kernel_bind_group_layouts: &[&wgpu::BindGroupLayout],
+    ) -> Self {
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Wisdom-Holman Params Buffer"),
+            contents: bytemuck::cast_slice(&[WisdomHolmanParams {
+                central_id: config.central_id.unwrap_or(0),
+                use_id: config.central_id.is_some() as u32,
+                _padding: [0; 2],
+            }]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let state_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Wisdom-Holman State Buffer"),
+            size: STATE_SIZE as _,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Wisdom-Holman Bind Group Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(std::mem::size_of::<
+                            WisdomHolmanParams,
+                        >() as _),
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(STATE_SIZE as _),
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Wisdom-Holman Bind Group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: state_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        let compute_module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Wisdom-Holman Module"),
+            source: wgpu::ShaderSource::Wgsl(
+                ForceLaw::Newtonian
+                    .kernel_source(
+                        include_str!("shaders/naive.wgsl"),
+                        include_str!("shaders/wisdom_holman.wgsl"),
+                    )
+                    .into(),
+            ),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Wisdom-Holman Pipeline Layout"),
+            bind_group_layouts: &[kernel_bind_group_layouts, &[&bind_group_layout]].concat(),
+            push_constant_ranges: &[],
+        });
+        let create_pipeline = |entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: &compute_module,
+                entry_point,
+            })
+        };
+
+        Self {
+            bind_group,
+            frame_pipeline: create_pipeline("wh_frame"),
+            kick_drift_pipeline: create_pipeline("wh_kick_drift"),
+            momentum_pipeline: create_pipeline("wh_momentum"),
+            jump_pipeline: create_pipeline("wh_jump"),
+            kick_pipeline: create_pipeline("wh_kick"),
+            finish_pipeline: create_pipeline("wh_finish"),
+            restore_pipeline: create_pipeline("wh_restore"),
+        }
+    }
+
+    /// Records one step. `kernel_bind_groups` are the naive kernel's bind groups, the first one
+    /// reading the previous state and writing the next.
+    pub fn encode(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        kernel_bind_groups: &[&wgpu::BindGroup],
+        work_group_count: u32,
+    ) {
+        encoder.push_debug_group("Wisdom-Holman step");
+        {
+            let mut cpass =
+                encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+            for (i, bind_group) in kernel_bind_groups.iter().enumerate() {
+                cpass.set_bind_group(i as u32, bind_group, &[]);
+            }
+            cpass.set_bind_group(kernel_bind_groups.len() as u32, &self.bind_group, &[]);
+            for (pipeline, serial) in [
+                (&self.frame_pipeline, true),
+                (&self.kick_drift_pipeline, false),
+                (&self.momentum_pipeline, true),
+                (&self.jump_pipeline, false),
+                (&self.kick_pipeline, false),
+                (&self.finish_pipeline, true),
+                (&self.restore_pipeline, false),
+            ] {
+                cpass.set_pipeline(pipeline);
+                match serial {
+                    true => cpass.dispatch(1, 1, 1),
+                    false => cpass.dispatch(work_group_count, 1, 1),
+                }
+            }
+        }
+        encoder.pop_debug_group();
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+use bytemuck::Zeroable;
+use log::warn;
+
+use crate::sims::{Particle, SimParams, Species};
+
+const MAGIC: [u8; 8] = *b"NBODYTRJ";
+const VERSION: u32 = 1;
+
+/// Written once at the start of every trajectory file.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct FileHeader {
+    magic: [u8; 8],
+    version: u32,
+    /// Size of a `Particle` record, used to reject files written with a different layout
+    particle_size: u32,
+    sim_params: SimParams,
+}
+
+/// Precedes the particle records of every frame.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct FrameHeader {
+    step: u64,
+    time: f64,
+    particle_num: u32,
+    _padding: u32,
+}
+
+/// A single recorded snapshot of the particle set.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub step: u64,
+    pub time: f64,
+    pub particles: Vec<Particle>,
+}
+
+/// Appends frames to a trajectory file. Frames are written sequentially so a run can be
+/// inspected (or interrupted) before it finishes.
+pub struct TrajectoryWriter {
+    out: BufWriter<File>,
+    /// species table deciding which particles are written, everything when empty
+    species: Vec<Species>,
+}
+
+impl TrajectoryWriter {
+    pub fn create(path: impl AsRef<Path>, sim_params: SimParams) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create trajectory file {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        let header = FileHeader {
+            magic: MAGIC,
+            version: VERSION,
+            particle_size: std::mem::size_of::<Particle>() as u32,
+            sim_params,
+        };
+        out.write_all(bytemuck::bytes_of(&header))?;
+        Ok(Self {
+            out,
+            species: Vec::new(),
+        })
+    }
+
+    /// Only writes particles whose species has `output` set in following frames.
+    pub fn set_species(&mut self, species: &[Species]) {
+        self.species = species.to_vec();
+    }
+
+    /// Appends a frame. Particles should be in ID order (see `sims::sort_by_id`) so that playback
+    /// can interpolate between frames.
+    pub fn write_frame(
+        &mut self,
+        step: u64,
+        time: f64,
+        particles: &[Particle],
+    ) -> anyhow::Result<()> {
+        let filtered: Vec<Particle>;
+        let particles = match self.species.iter().all(|s| s.output) {
+            true => particles,
+            false => {
+                filtered = particles
+                    .iter()
+                    .filter(|p| Species::is_output(&self.species, p.species))
+                    .copied()
+                    .collect();
+                &filtered
+            }
+        };
+        let header = FrameHeader {
+            step,
+            time,
+            particle_num: particles.len() as u32,
+            _padding: 0,
+        };
+        self.out.write_all(bytemuck::bytes_of(&header))?;
+        self.out.write_all(bytemuck::cast_slice(particles))?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.out.flush().context("Failed to flush trajectory file")
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct FrameEntry {
+    offset: u64,
+    header: FrameHeader,
+}
+
+/// Random access to the frames of a trajectory file. Only the frame index is held in memory,
+/// particle data is read from disk on request.
+pub struct TrajectoryReader {
+    file: BufReader<File>,
+    sim_params: SimParams,
+    frames: Vec<FrameEntry>,
+}
+
+impl TrajectoryReader {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open trajectory file {}", path.display()))?;
+        let file_len = file.metadata()?.len();
+        let mut file = BufReader::new(file);
+
+        let mut header = FileHeader::zeroed();
+        file.read_exact(bytemuck::bytes_of_mut(&mut header))
+            .context("Trajectory file is missing its header")?;
+        if header.magic != MAGIC {
+            bail!("{} is not a trajectory file", path.display());
+        }
+        if header.version != VERSION {
+            bail!("Unsupported trajectory version {}", header.version);
+        }
+        if header.particle_size as usize != std::mem::size_of::<Particle>() {
+            bail!(
+                "Trajectory was recorded with {} byte particles, expected {}",
+                header.particle_size,
+                std::mem::size_of::<Particle>()
+            );
+        }
+
+        // build frame index by hopping between frame headers
+        let mut frames = Vec::new();
+        let mut offset = std::mem::size_of::<FileHeader>() as u64;
+        let frame_header_size = std::mem::size_of::<FrameHeader>() as u64;
+        while offset + frame_header_size <= file_len {
+            let mut frame_header = FrameHeader::zeroed();
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(bytemuck::bytes_of_mut(&mut frame_header))?;
+            let frame_len =
+                frame_header_size + frame_header.particle_num as u64 * header.particle_size as u64;
+            if offset + frame_len > file_len {
+                warn!("Ignoring truncated frame at step {}", frame_header.step);
+                break;
+            }
+            frames.push(FrameEntry {
+                offset,
+                header: frame_header,
+            });
+            offset += frame_len;
+        }
+
+        Ok(Self {
+            file,
+            sim_params: header.sim_params,
+            frames,
+        })
+    }
+
+    pub fn sim_params(&self) -> SimParams {
+        self.sim_params
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Largest particle count of any frame, useful for sizing buffers up front.
+    pub fn max_particle_num(&self) -> u32 {
+        self.frames
+            .iter()
+            .map(|f| f.header.particle_num)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn read_frame(&mut self, ix: usize) -> anyhow::Result<Frame> {
+        let entry = *self
+            .frames
+            .get(ix)
+            .with_context(|| format!("Frame {} out of range", ix))?;
+        let mut particles = vec![Particle::zeroed(); entry.header.particle_num as usize];
+        self.file.seek(SeekFrom::Start(
+            entry.offset + std::mem::size_of::<FrameHeader>() as u64,
+        ))?;
+        self.file
+            .read_exact(bytemuck::cast_slice_mut(&mut particles))
+            .with_context(|| format!("Failed to read frame {}", ix))?;
+        Ok(Frame {
+            step: entry.header.step,
+            time: entry.header.time,
+            particles,
+        })
+    }
+}
@@ -48,14 +48,19 @@ impl<'a, T> SliceAlloc<'a, T> {
     /// Writes a given value to the end of the currently used space in the slice. Returns a
     /// `Reserve` type which can be used to read and write to that location with the guaruntee that
     /// no other thread may be reading or writing it concurrently. **NOTE**: This only holds if
-    /// `Reserve` values are only passed to the `SliceAlloc`s that issue it.
-    pub fn write(&mut self, value: T) -> Reserve<'a> {
+    /// `Reserve` values are only passed to the `SliceAlloc`s that issue it. Returns `None` when the
+    /// slice is full.
+    pub fn try_write(&mut self, value: T) -> Option<Reserve<'a>> {
         let ix = self.alloced.fetch_add(1, Ordering::Relaxed);
+        if ix >= self.inner.len() {
+            self.alloced.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
         self.inner[ix] = value;
-        Reserve {
+        Some(Reserve {
             ix,
             phantom: PhantomData,
-        }
+        })
     }
 
     pub fn len(&self) -> usize {
@@ -0,0 +1,52 @@
+use bytemuck::Zeroable;
+use wgpu_n_body::{
+    runners::OfflineHeadless,
+    sims::{AdaptiveTimestep, AddParams, Particle, SimParams, TreeSim},
+};
+
+const PAIRS: u32 = 4;
+
+/// Pairs of overlapping bodies, far apart from each other, that merge on the first step.
+fn overlapping_pairs(sim_params: &SimParams) -> Vec<Particle> {
+    (0..sim_params.particle_num)
+        .map(|i| Particle {
+            position: [(i / 2) as f32, 0.01 * (i % 2) as f32, 0.0],
+            mass: 1.0,
+            id: i,
+            radius: 0.1,
+            ..Zeroable::zeroed()
+        })
+        .collect()
+}
+
+#[test]
+fn collisions_with_adaptive_timestep() {
+    let sim_params = SimParams {
+        particle_num: 2 * PAIRS,
+        ..Default::default()
+    };
+    let runner = pollster::block_on(OfflineHeadless::<TreeSim>::new(
+        sim_params,
+        AddParams::TreeSimParams {
+            theta: 0.75,
+            collisions: true,
+        },
+        overlapping_pairs,
+    ));
+    let mut runner = match runner {
+        Ok(runner) => runner,
+        Err(err) => {
+            eprintln!("skipping, no usable GPU: {:#}", err);
+            return;
+        }
+    };
+    runner.set_adaptive_timestep(Some(AdaptiveTimestep::default()));
+    for _ in 0..3 {
+        runner.step();
+    }
+    assert_eq!(runner.particle_count(), PAIRS);
+    assert_eq!(runner.sim_params().particle_num, PAIRS);
+    let particles = runner.read_particles();
+    assert_eq!(particles.len(), PAIRS as usize);
+    assert!(particles.iter().all(|p| p.mass == 2.0));
+}
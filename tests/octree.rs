@@ -0,0 +1,211 @@
+use bytemuck::Zeroable;
+use rand::{distributions::Uniform, prelude::Distribution, rngs::StdRng, Rng, SeedableRng};
+use wgpu_n_body::sims::{Octree, Particle, TRAVERSAL_STACK_SIZE};
+
+fn particle(position: [f32; 3], mass: f32) -> Particle {
+    Particle {
+        position,
+        mass,
+        ..Zeroable::zeroed()
+    }
+}
+
+fn check(particles: &[Particle]) {
+    let octree = Octree::build(particles);
+    octree.validate(particles).unwrap();
+    let stats = octree.stats();
+    let massive = particles.iter().filter(|p| !p.is_tracer()).count();
+    assert_eq!(stats.nodes, octree.node_count());
+    assert_eq!(stats.depth_histogram.iter().sum::<usize>(), stats.nodes);
+    assert_eq!(stats.leaves, massive);
+    assert_eq!(
+        stats.occupancy.iter().sum::<usize>() + stats.leaves,
+        stats.nodes - (massive == 0) as usize
+    );
+    assert!(stats.max_stack_depth >= 1);
+}
+
+#[test]
+fn random_sets() {
+    let mut rng = StdRng::seed_from_u64(1);
+    for _ in 0..50 {
+        let len = rng.gen_range(0..2000);
+        let scale = 10f32.powi(rng.gen_range(-3..4));
+        let coord = Uniform::new_inclusive(-scale, scale);
+        let particles: Vec<Particle> = (0..len)
+            .map(|_| {
+                let mass = match rng.gen_bool(0.2) {
+                    true => 0.0,
+                    false => rng.gen_range(0.1..10.0),
+                };
+                let mut p = particle([0; 3].map(|_| coord.sample(&mut rng)), mass);
+                p.charge = rng.gen_range(-1.0..1.0);
+                p
+            })
+            .collect();
+        check(&particles);
+    }
+}
+
+#[test]
+fn clustered_sets() {
+    let mut rng = StdRng::seed_from_u64(2);
+    for _ in 0..20 {
+        let centers: Vec<[f32; 3]> = (0..rng.gen_range(1..5))
+            .map(|_| [0; 3].map(|_| rng.gen_range(-100.0..100.0)))
+            .collect();
+        let particles: Vec<Particle> = (0..1000)
+            .map(|i| {
+                let center = centers[i % centers.len()];
+                let spread = 10f32.powi(-rng.gen_range(1..6));
+                particle(center.map(|c| c + rng.gen_range(-spread..spread)), 1.0)
+            })
+            .collect();
+        check(&particles);
+    }
+}
+
+#[test]
+fn degenerate_sets() {
+    // empty, only tracers and a single body
+    check(&[]);
+    check(&[particle([1.0, 2.0, 3.0], 0.0); 10]);
+    check(&[particle([1.0, 2.0, 3.0], 1.0)]);
+    // coincident bodies, alone and next to others
+    for len in [2, 3, 9, 100] {
+        check(&vec![particle([0.5, -0.25, 4.0], 1.0); len]);
+        let mut particles = vec![particle([0.0; 3], 2.0); len];
+        particles.push(particle([1.0, 1.0, 1.0], 1.0));
+        check(&particles);
+    }
+    // everything at the origin
+    check(&[particle([0.0; 3], 1.0); 5]);
+    // points on a line and on a plane
+    let line: Vec<Particle> = (0..500)
+        .map(|i| particle([i as f32, 0.0, 0.0], 1.0))
+        .collect();
+    check(&line);
+    let plane: Vec<Particle> = (0..400)
+        .map(|i| particle([(i % 20) as f32, (i / 20) as f32, 0.0], 1.0))
+        .collect();
+    check(&plane);
+    // nearly coincident pairs far from the origin
+    let pairs: Vec<Particle> = (0..200)
+        .map(|i| particle([1e4 + (i / 2) as f32, 1e-7 * (i % 2) as f32, 0.0], 1.0))
+        .collect();
+    check(&pairs);
+}
+
+#[test]
+fn nested_clusters_fit_the_traversal_stack() {
+    // every level fills the 7 octants around the next, smaller level in the last octant, so
+    // splitting as deep as possible leaves 7 siblings waiting on the stack per level
+    let particles: Vec<Particle> = (0..20)
+        .flat_map(|level| {
+            let width = 0.5f32.powi(level);
+            (0..7).map(move |corner| {
+                let offset = |axis: usize| match corner >> axis & 1 {
+                    0 => 0.25,
+                    _ => 0.75,
+                };
+                particle(
+                    [0, 1, 2].map(|axis| 1.0 - width + width * offset(axis)),
+                    1.0,
+                )
+            })
+        })
+        .collect();
+    check(&particles);
+    let octree = Octree::build(&particles);
+    assert!(octree.stats().max_stack_depth <= TRAVERSAL_STACK_SIZE);
+}
+
+#[test]
+fn validate_rejects_other_particles() {
+    let particles: Vec<Particle> = (0..10)
+        .map(|i| particle([i as f32, 0.0, 0.0], 1.0))
+        .collect();
+    let octree = Octree::build(&particles);
+    let mut moved = particles.clone();
+    moved[3].position[1] = 5.0;
+    assert!(octree.validate(&moved).is_err());
+    assert!(octree.validate(&particles[1..]).is_err());
+}
+
+fn distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+#[test]
+fn queries_match_brute_force() {
+    let mut rng = StdRng::seed_from_u64(3);
+    for round in 0..20 {
+        let len = rng.gen_range(1..1500);
+        let particles: Vec<Particle> = (0..len)
+            .map(|i| {
+                // every fourth round piles bodies onto a few points
+                let position = match round % 4 == 0 {
+                    true => [(i % 7) as f32, 0.5, -1.0],
+                    false => [0; 3].map(|_| rng.gen_range(-1.0..1.0)),
+                };
+                particle(position, rng.gen_range(0..5) as f32)
+            })
+            .collect();
+        let massive: Vec<u32> = (0..len as u32)
+            .filter(|&i| !particles[i as usize].is_tracer())
+            .collect();
+        let octree = Octree::build(&particles);
+
+        let mut order = octree.locality_order();
+        order.sort_unstable();
+        assert_eq!(order, massive);
+
+        let points: Vec<[f32; 3]> = (0..20)
+            .map(|_| [0; 3].map(|_| rng.gen_range(-1.2..1.2)))
+            .collect();
+        let k = rng.gen_range(1..20);
+        let radius = rng.gen_range(0.0..0.8);
+        let nearest = octree.nearest_batch(&points, k);
+        let within = octree.within_radius_batch(&points, radius);
+        for (ix, point) in points.iter().enumerate() {
+            let mut expected: Vec<f32> = massive
+                .iter()
+                .map(|&i| distance(point, &particles[i as usize].position))
+                .collect();
+            expected.sort_by(f32::total_cmp);
+            expected.truncate(k);
+            let found: Vec<f32> = nearest[ix].iter().map(|&(_, d)| d).collect();
+            assert_eq!(found, expected);
+            for &(i, d) in &nearest[ix] {
+                assert_eq!(d, distance(point, &particles[i as usize].position));
+            }
+
+            let mut expected: Vec<u32> = massive
+                .iter()
+                .copied()
+                .filter(|&i| distance(point, &particles[i as usize].position) <= radius)
+                .collect();
+            let mut found = within[ix].clone();
+            found.sort_unstable();
+            expected.sort_unstable();
+            assert_eq!(found, expected);
+        }
+
+        let boxes: Vec<([f32; 3], [f32; 3])> = points
+            .iter()
+            .map(|p| (p.map(|x| x - radius), p.map(|x| x + radius / 2.0)))
+            .collect();
+        for ((min, max), mut found) in boxes.iter().zip(octree.in_box_batch(&boxes)) {
+            let expected: Vec<u32> = massive
+                .iter()
+                .copied()
+                .filter(|&i| {
+                    let p = particles[i as usize].position;
+                    (0..3).all(|k| min[k] <= p[k] && p[k] <= max[k])
+                })
+                .collect();
+            found.sort_unstable();
+            assert_eq!(found, expected);
+        }
+    }
+}